@@ -0,0 +1,599 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cfg::CfgFlag,
+    profile::Profile,
+    state::{self, State},
+    workspace::Workspace,
+};
+
+const BEGIN_MARKER: &str = "# BEGIN cargo-pbuild";
+const END_MARKER: &str = "# END cargo-pbuild";
+
+/// Set in `.vscode/settings.json` to the activated profile, marking the
+/// rust-analyzer settings as written by us.
+const VSCODE_MARKER: &str = "cargo-pbuild.profile";
+
+/// rust-analyzer's own default for `rust-analyzer.cargo.cfgs`, which the
+/// setting replaces rather than extends.
+const RUST_ANALYZER_DEFAULT_CFGS: [&str; 2] = ["debug_assertions", "miri"];
+
+/// The rust-analyzer settings written to `.vscode/settings.json`.
+const VSCODE_KEYS: [&str; 4] = [
+    "rust-analyzer.cargo.cfgs",
+    "rust-analyzer.cargo.features",
+    "rust-analyzer.cargo.target",
+    "rust-analyzer.cargo.extraEnv",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not access `{0}`.")]
+    Io(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not parse `{0}`.")]
+    Toml(PathBuf, #[source] toml::de::Error),
+
+    #[error("Could not parse `{0}`. Comments and trailing commas are not supported.")]
+    Json(PathBuf, #[source] serde_json::Error),
+
+    #[error("`{0}` already sets `{1}`; remove it before activating a profile.")]
+    Conflict(PathBuf, String),
+
+    #[error("`{0}` defines `[{1}]` without a table header; rewrite it as `[{1}]` before activating a profile.")]
+    UnsupportedLayout(PathBuf, String),
+
+    #[error("`{0}` is not a JSON object.")]
+    NotAnObject(PathBuf),
+
+    #[error("Could not update state.")]
+    State(#[from] state::Error),
+}
+
+/// Which files to write when activating a profile.
+#[derive(Debug, Clone, Copy)]
+pub struct Targets {
+    pub cargo_config: bool,
+    pub vscode: bool,
+}
+
+fn cargo_config_path(workspace: &Workspace) -> PathBuf {
+    workspace.root.join(".cargo").join("config.toml")
+}
+
+fn vscode_settings_path(workspace: &Workspace) -> PathBuf {
+    workspace.root.join(".vscode").join("settings.json")
+}
+
+/// The contents of the files [`activate`] writes, or `None` for a file that
+/// does not exist. Both are read and parsed before either is changed.
+#[derive(Debug, Clone, PartialEq)]
+struct Files {
+    cargo_config: Option<String>,
+    vscode_settings: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Files {
+    fn read(workspace: &Workspace) -> Result<Files, Error> {
+        let path = cargo_config_path(workspace);
+        let cargo_config = if path.is_file() {
+            Some(read(&path)?)
+        } else {
+            None
+        };
+        let path = vscode_settings_path(workspace);
+        let vscode_settings = if path.is_file() {
+            Some(read_json(&path)?)
+        } else {
+            None
+        };
+        Ok(Files {
+            cargo_config,
+            vscode_settings,
+        })
+    }
+
+    /// Writes or removes each file that differs from `original`.
+    fn write(&self, workspace: &Workspace, original: &Files) -> Result<(), Error> {
+        if self.cargo_config != original.cargo_config {
+            let path = cargo_config_path(workspace);
+            match self.cargo_config.as_ref() {
+                Some(v) => write(&path, v)?,
+                None => remove(&path)?,
+            }
+        }
+        if self.vscode_settings != original.vscode_settings {
+            let path = vscode_settings_path(workspace);
+            match self.vscode_settings.as_ref() {
+                Some(v) => write_json(&path, v)?,
+                None => remove(&path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the profile's flags into `.cargo/config.toml` and its
+/// rust-analyzer settings into `.vscode/settings.json`, replacing any
+/// previously activated profile.
+///
+/// Existing content in either file is kept: the Cargo config only gains
+/// lines between markers, and the previous value of each VS Code setting is
+/// recorded so [`deactivate`] can restore it. Nothing is written unless both
+/// files can be updated.
+pub fn activate(
+    workspace: &Workspace,
+    name: &str,
+    profile: &Profile,
+    targets: Targets,
+) -> Result<(), Error> {
+    let mut state = State::load(workspace)?;
+    let original = Files::read(workspace)?;
+    let mut files = original.clone();
+    unmark(&mut files, take_active(&mut state).as_ref());
+
+    let mut active = serde_json::Map::new();
+    active.insert("profile".into(), name.into());
+
+    if targets.cargo_config {
+        let path = cargo_config_path(workspace);
+        let raw = files.cargo_config.as_deref().unwrap_or_default();
+        let out = add_cargo_config(&path, raw, name, profile)?;
+        active.insert(
+            "cargo_config_created".into(),
+            files.cargo_config.is_none().into(),
+        );
+        files.cargo_config = Some(out);
+    }
+
+    if targets.vscode {
+        active.insert(
+            "vscode_created".into(),
+            files.vscode_settings.is_none().into(),
+        );
+        let settings = files.vscode_settings.get_or_insert_with(Default::default);
+        let previous = add_vscode_settings(settings, name, profile);
+        active.insert("vscode_previous".into(), previous.into());
+    }
+
+    files.write(workspace, &original)?;
+    state.set("active", active.into());
+    state.save()?;
+    Ok(())
+}
+
+/// Undoes [`activate`]. Returns the name of the profile that was active.
+///
+/// The state recording what was activated lives under `target/` and is lost
+/// on `cargo clean`, so the marked blocks and settings are removed even
+/// without it; only the settings' previous values are then lost.
+pub fn deactivate(workspace: &Workspace) -> Result<Option<String>, Error> {
+    let mut state = State::load(workspace)?;
+    let original = Files::read(workspace)?;
+    let mut files = original.clone();
+    let active = take_active(&mut state);
+    let name = unmark(&mut files, active.as_ref());
+
+    files.write(workspace, &original)?;
+    if active.is_some() {
+        state.save()?;
+    }
+    Ok(name)
+}
+
+/// Removes the record of the active profile from the state.
+fn take_active(state: &mut State) -> Option<serde_json::Map<String, serde_json::Value>> {
+    match state.remove("active") {
+        Some(serde_json::Value::Object(v)) => Some(v),
+        _ => None,
+    }
+}
+
+/// Removes what [`activate`] added to the files, returning the name of the
+/// profile that was active.
+fn unmark(
+    files: &mut Files,
+    active: Option<&serde_json::Map<String, serde_json::Value>>,
+) -> Option<String> {
+    let is_set =
+        |key: &str| active.and_then(|x| x.get(key)).and_then(|x| x.as_bool()) == Some(true);
+    let mut name = active
+        .and_then(|x| x.get("profile"))
+        .and_then(|x| x.as_str())
+        .map(str::to_string);
+
+    if let Some(raw) = files.cargo_config.as_ref() {
+        if raw.lines().any(|x| x.trim().starts_with(BEGIN_MARKER)) {
+            name = name.or_else(|| marked_profile(raw));
+            let stripped = strip_blocks(raw);
+            // Without state, a file holding nothing but our blocks can only
+            // have been created by us.
+            let created = active.is_none() || is_set("cargo_config_created");
+            files.cargo_config = if stripped.trim().is_empty() && created {
+                None
+            } else {
+                Some(stripped)
+            };
+        }
+    }
+
+    if let Some(settings) = files.vscode_settings.as_mut() {
+        if let Some(v) = settings.remove(VSCODE_MARKER) {
+            name = name.or_else(|| v.as_str().map(str::to_string));
+            let previous = active
+                .and_then(|x| x.get("vscode_previous"))
+                .and_then(|x| x.as_object());
+            for key in VSCODE_KEYS {
+                match previous.and_then(|x| x.get(key)) {
+                    Some(v) if !v.is_null() => settings.insert(key.to_string(), v.clone()),
+                    _ => settings.remove(key),
+                };
+            }
+            let created = active.is_none() || is_set("vscode_created");
+            if settings.is_empty() && created {
+                files.vscode_settings = None;
+            }
+        }
+    }
+
+    name
+}
+
+/// The profile named by the first marked block in a file.
+fn marked_profile(s: &str) -> Option<String> {
+    s.lines()
+        .map(str::trim)
+        .find(|x| x.starts_with(BEGIN_MARKER))?
+        .split('`')
+        .nth(1)
+        .map(str::to_string)
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(v) => Ok(v),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(Error::Io(path.to_path_buf(), e)),
+    }
+}
+
+/// Removes a file we created, along with its directory if now empty.
+fn remove(path: &Path) -> Result<(), Error> {
+    std::fs::remove_file(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::remove_dir(parent);
+    }
+    Ok(())
+}
+
+fn write(path: &Path, s: &str) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    }
+    std::fs::write(path, s).map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Removes every block written by [`activate`] from a file's contents,
+/// markers included.
+pub(crate) fn strip_blocks(s: &str) -> String {
+    let mut out = String::new();
+    let mut in_block = false;
+    for line in s.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with(BEGIN_MARKER) {
+            in_block = true;
+        } else if trimmed.starts_with(END_MARKER) {
+            in_block = false;
+        } else if !in_block {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    let len = out.trim_end().len();
+    out.truncate(len);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// Adds the profile's blocks to the contents of a Cargo config file, which
+/// must not already contain any.
+fn add_cargo_config(
+    path: &Path,
+    raw: &str,
+    name: &str,
+    profile: &Profile,
+) -> Result<String, Error> {
+    let existing: toml::Value = toml::from_str(raw).map_err(|e| Error::Toml(path.into(), e))?;
+
+    let mut build = vec![(
+        "rustflags".to_string(),
+        toml::Value::Array(
+            profile
+                .rustc_flags()
+                .into_iter()
+                .map(toml::Value::String)
+                .collect(),
+        ),
+    )];
+    if let Some(target) = profile.target.as_ref() {
+        build.push(("target".into(), toml::Value::String(target.to_string())));
+    }
+    let env = profile
+        .env
+        .iter()
+        .map(|(k, v)| (k.to_string(), toml::Value::String(v.to_string())))
+        .collect::<Vec<_>>();
+
+    let mut tables = vec![("build", build)];
+    if !env.is_empty() {
+        tables.push(("env", env));
+    }
+
+    let mut lines = raw.lines().map(str::to_string).collect::<Vec<_>>();
+    for (table, entries) in tables {
+        for (key, _) in entries.iter() {
+            if existing.get(table).and_then(|x| x.get(key)).is_some() {
+                return Err(Error::Conflict(path.into(), format!("{}.{}", table, key)));
+            }
+        }
+
+        let mut block = vec![format!("{} (profile `{}`)", BEGIN_MARKER, name)];
+        if table == "build" && !profile.features.is_empty() {
+            block.push(format!(
+                "# features: {} (pass with --features; Cargo config cannot set them)",
+                profile.features.join(",")
+            ));
+        }
+        block.extend(
+            entries
+                .iter()
+                .map(|(k, v)| format!("{} = {}", toml_key(k), v)),
+        );
+        block.push(END_MARKER.to_string());
+
+        let header = format!("[{}]", table);
+        match lines.iter().position(|x| x.trim() == header) {
+            Some(i) => {
+                lines.splice(i + 1..i + 1, block);
+            }
+            None if existing.get(table).is_some() => {
+                return Err(Error::UnsupportedLayout(path.into(), table.to_string()));
+            }
+            None => {
+                if lines.last().map(|x| !x.trim().is_empty()).unwrap_or(false) {
+                    lines.push(String::new());
+                }
+                lines.push(block.remove(0));
+                lines.push(header);
+                lines.extend(block);
+            }
+        }
+    }
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+    Ok(out)
+}
+
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+    let raw = read(path)?;
+    if raw.trim().is_empty() {
+        return Ok(Default::default());
+    }
+    match serde_json::from_str(&raw).map_err(|e| Error::Json(path.into(), e))? {
+        serde_json::Value::Object(v) => Ok(v),
+        _ => Err(Error::NotAnObject(path.into())),
+    }
+}
+
+fn write_json(
+    path: &Path,
+    value: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Error> {
+    let mut out = serde_json::to_string_pretty(value).unwrap();
+    out.push('\n');
+    write(path, &out)
+}
+
+/// Sets the rust-analyzer settings, returning their previous values.
+fn add_vscode_settings(
+    settings: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    profile: &Profile,
+) -> serde_json::Map<String, serde_json::Value> {
+    let previous = VSCODE_KEYS
+        .iter()
+        .map(|k| {
+            (
+                k.to_string(),
+                settings.get(*k).cloned().unwrap_or(serde_json::Value::Null),
+            )
+        })
+        .collect();
+
+    let cfgs = RUST_ANALYZER_DEFAULT_CFGS
+        .iter()
+        .map(|x| x.to_string())
+        .chain(profile.cfg_flags().iter().map(rust_analyzer_cfg))
+        .collect::<Vec<_>>();
+    settings.insert(VSCODE_MARKER.into(), name.into());
+    settings.insert(VSCODE_KEYS[0].into(), cfgs.into());
+    settings.insert(VSCODE_KEYS[1].into(), profile.features.clone().into());
+    match profile.target.as_ref() {
+        Some(target) => settings.insert(VSCODE_KEYS[2].into(), target.as_str().into()),
+        None => settings.remove(VSCODE_KEYS[2]),
+    };
+    if profile.env.is_empty() {
+        settings.remove(VSCODE_KEYS[3]);
+    } else {
+        let env = profile
+            .env
+            .iter()
+            .map(|(k, v)| (k.to_string(), serde_json::Value::from(v.as_str())))
+            .collect::<serde_json::Map<_, _>>();
+        settings.insert(VSCODE_KEYS[3].into(), env.into());
+    }
+
+    previous
+}
+
+/// rust-analyzer takes cfgs as `name` or `name=value`, without quotes.
+fn rust_analyzer_cfg(flag: &CfgFlag) -> String {
+    match &flag.value {
+        Some(v) => format!("{}={}", flag.name, v),
+        None => flag.name.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::Spec;
+
+    /// A workspace rooted in a fresh directory under the system temp dir.
+    fn workspace(name: &str) -> Workspace {
+        let root =
+            std::env::temp_dir().join(format!("pbuild-activate-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        Workspace {
+            profiles_dir: root.join("profiles"),
+            specs_dir: root.join("profiles").join("specs"),
+            dist_dir: root.join("dist"),
+            target_dir: root.join("target"),
+            artifact_name: Default::default(),
+            default_profile: None,
+            lints: Default::default(),
+            root,
+        }
+    }
+
+    fn profile() -> Profile {
+        let spec = Spec::parse_str(
+            "[spec]\nname = \"main\"\n\
+             [spec.types]\nboard = { key = \"board\", single = true }\n\
+             [board.dk]\ndescription = \"DK\"\n",
+        )
+        .unwrap();
+        Profile::parse_str(
+            &spec,
+            "[profile]\ndescription = \"Dev\"\nbins = [\"app\"]\nfeatures = [\"log\"]\n\
+             [config]\nboard = \"dk\"\n[env]\nDEFMT_LOG = \"info\"\n",
+        )
+        .unwrap()
+    }
+
+    const BOTH: Targets = Targets {
+        cargo_config: true,
+        vscode: true,
+    };
+
+    #[test]
+    fn cargo_config_block_goes_under_an_existing_header() {
+        let raw = "[alias]\nb = \"build\"\n\n[build]\njobs = 4\n";
+        let out = add_cargo_config(Path::new("config.toml"), raw, "dev", &profile()).unwrap();
+
+        let lines = out.lines().collect::<Vec<_>>();
+        let header = lines.iter().position(|x| *x == "[build]").unwrap();
+        assert!(lines[header + 1].starts_with(BEGIN_MARKER));
+        assert!(lines.contains(&"jobs = 4"));
+        assert!(lines.contains(&"[env]"));
+        toml::from_str::<toml::Value>(&out).unwrap();
+
+        assert_eq!(strip_blocks(&out), raw);
+    }
+
+    #[test]
+    fn cargo_config_keys_already_set_conflict() {
+        let raw = "[env]\nDEFMT_LOG = \"debug\"\n";
+        match add_cargo_config(Path::new("config.toml"), raw, "dev", &profile()) {
+            Err(Error::Conflict(_, key)) => assert_eq!(key, "env.DEFMT_LOG"),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cargo_config_tables_without_a_header_are_unsupported() {
+        let raw = "build.jobs = 4\n";
+        match add_cargo_config(Path::new("config.toml"), raw, "dev", &profile()) {
+            Err(Error::UnsupportedLayout(_, table)) => assert_eq!(table, "build"),
+            other => panic!("expected an unsupported layout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deactivate_restores_previous_settings() {
+        let workspace = workspace("restore");
+        let cargo_config = "[alias]\nb = \"build\"\n";
+        let settings =
+            "{\n  \"editor.tabSize\": 2,\n  \"rust-analyzer.cargo.features\": [\"x\"]\n}\n";
+        write(&cargo_config_path(&workspace), cargo_config).unwrap();
+        write(&vscode_settings_path(&workspace), settings).unwrap();
+
+        activate(&workspace, "dev", &profile(), BOTH).unwrap();
+        let active = read_json(&vscode_settings_path(&workspace)).unwrap();
+        assert_eq!(active[VSCODE_MARKER], "dev");
+        assert_eq!(
+            active["rust-analyzer.cargo.features"],
+            serde_json::json!(["log"])
+        );
+        assert_eq!(
+            active["rust-analyzer.cargo.cfgs"],
+            serde_json::json!(["debug_assertions", "miri", "board=dk"])
+        );
+
+        assert_eq!(deactivate(&workspace).unwrap().as_deref(), Some("dev"));
+        assert_eq!(read(&cargo_config_path(&workspace)).unwrap(), cargo_config);
+        assert_eq!(
+            serde_json::Value::from(read_json(&vscode_settings_path(&workspace)).unwrap()),
+            serde_json::from_str::<serde_json::Value>(settings).unwrap()
+        );
+        assert_eq!(deactivate(&workspace).unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(&workspace.root);
+    }
+
+    #[test]
+    fn deactivate_without_state_uses_the_markers() {
+        let workspace = workspace("no-state");
+        assert_eq!(deactivate(&workspace).unwrap(), None);
+
+        activate(&workspace, "dev", &profile(), BOTH).unwrap();
+        std::fs::remove_dir_all(&workspace.target_dir).unwrap();
+
+        assert_eq!(deactivate(&workspace).unwrap().as_deref(), Some("dev"));
+        assert!(!cargo_config_path(&workspace).exists());
+        assert!(!vscode_settings_path(&workspace).exists());
+
+        let _ = std::fs::remove_dir_all(&workspace.root);
+    }
+
+    #[test]
+    fn invalid_settings_leave_both_files_untouched() {
+        let workspace = workspace("invalid");
+        let settings = "{\n  // comment\n}\n";
+        write(&vscode_settings_path(&workspace), settings).unwrap();
+
+        let err = activate(&workspace, "dev", &profile(), BOTH).unwrap_err();
+        assert!(matches!(err, Error::Json(..)), "{:?}", err);
+        assert!(!cargo_config_path(&workspace).exists());
+        assert!(!workspace.state_path().exists());
+        assert_eq!(read(&vscode_settings_path(&workspace)).unwrap(), settings);
+
+        let _ = std::fs::remove_dir_all(&workspace.root);
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    manifest,
+    profile::Profile,
+    workspace::{LoadError, Workspace},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not run cargo.")]
+    Cargo(#[source] std::io::Error),
+
+    #[error("Cargo exited unsuccessfully.")]
+    Failed(Option<i32>),
+
+    #[error("Could not read package versions from `cargo metadata`: {0}.")]
+    Metadata(String),
+
+    #[error("Could not write build log `{0}`.")]
+    Log(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not copy `{0}` to `{1}`.")]
+    Copy(PathBuf, PathBuf, #[source] std::io::Error),
+
+    #[error("Could not write build manifest `{0}`.")]
+    Manifest(PathBuf, #[source] std::io::Error),
+
+    #[error("`{0}` cannot be used when building profiles, as the binaries are found in cargo's JSON messages.")]
+    MessageFormat(String),
+
+    #[error("Cargo did not report building binary `{0}`.")]
+    MissingBinary(String),
+}
+
+/// A template for the file names of collected binaries, such as
+/// `{bin}-{profile}-{version}`. The platform's executable extension, if any,
+/// is appended.
+#[derive(Debug, Clone)]
+pub struct ArtifactName(String);
+
+impl ArtifactName {
+    const PLACEHOLDERS: [&'static str; 4] = ["bin", "package", "profile", "version"];
+
+    /// Renders the template, taking values in the order of
+    /// [`ArtifactName::PLACEHOLDERS`].
+    fn render(&self, values: [&str; 4]) -> String {
+        let mut out = self.0.clone();
+        for (k, v) in Self::PLACEHOLDERS.iter().zip(values) {
+            out = out.replace(&format!("{{{}}}", k), v);
+        }
+        out
+    }
+}
+
+impl Default for ArtifactName {
+    fn default() -> Self {
+        ArtifactName("{bin}-{profile}-{version}".into())
+    }
+}
+
+impl FromStr for ArtifactName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed `{{` in `{}`", s))?;
+            let name = &rest[start + 1..start + end];
+            if !Self::PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder `{{{}}}`, expected one of {}",
+                    name,
+                    Self::PLACEHOLDERS
+                        .iter()
+                        .map(|x| format!("`{{{}}}`", x))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if s.contains(['/', '\\']) {
+            return Err(format!("`{}` must not contain path separators", s));
+        }
+        Ok(ArtifactName(s.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum Status {
+    /// The build succeeded, producing the given files in the dist directory.
+    Success(Vec<PathBuf>),
+    /// The build was skipped as nothing changed since the given files were
+    /// built.
+    Unchanged(Vec<PathBuf>),
+    /// The profile could not be loaded.
+    Load(LoadError),
+    /// The build failed.
+    Failed(Error),
+}
+
+impl Status {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Status::Success(_) | Status::Unchanged(_))
+    }
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+    pub status: Status,
+    pub duration: Duration,
+    /// Where cargo's output went, if it was not shown directly.
+    pub log: Option<PathBuf>,
+}
+
+/// Builds each profile, running up to `jobs` profiles at once, and returns
+/// the outcomes in the order given.
+///
+/// A failing profile does not stop the others. When more than one job runs,
+/// cargo's output is written to `build.log` in each profile's target
+/// directory instead of the terminal. With `skip_unchanged`, profiles are
+/// not rebuilt if [`manifest::unchanged_artifacts`] finds nothing changed.
+pub fn build_profiles(
+    workspace: &Workspace,
+    names: &[String],
+    extra_args: &[String],
+    jobs: usize,
+    skip_unchanged: bool,
+) -> Vec<Outcome> {
+    let jobs = jobs.max(1).min(names.len().max(1));
+    let queue = Mutex::new(names.iter().enumerate().collect::<VecDeque<_>>());
+    let results = Mutex::new((0..names.len()).map(|_| None).collect::<Vec<_>>());
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (i, name) = match next {
+                    Some(v) => v,
+                    None => break,
+                };
+                if jobs > 1 {
+                    eprintln!("Building `{}`...", name);
+                }
+                let outcome = build_named(workspace, name, extra_args, jobs > 1, skip_unchanged);
+                if jobs > 1 {
+                    eprintln!(
+                        "Finished `{}`: {}",
+                        name,
+                        if outcome.status.is_success() {
+                            "ok"
+                        } else {
+                            "FAILED"
+                        }
+                    );
+                }
+                results.lock().unwrap()[i] = Some(outcome);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.unwrap())
+        .collect()
+}
+
+fn build_named(
+    workspace: &Workspace,
+    name: &str,
+    extra_args: &[String],
+    log: bool,
+    skip_unchanged: bool,
+) -> Outcome {
+    let start = Instant::now();
+    let log = if log {
+        Some(workspace.profile_target_dir(name).join("build.log"))
+    } else {
+        None
+    };
+
+    let status = match workspace.load_profile(name) {
+        Ok(profile) => {
+            let unchanged = if skip_unchanged {
+                manifest::unchanged_artifacts(
+                    &workspace.root,
+                    &workspace.profile_dist_dir(name),
+                    &profile,
+                    extra_args,
+                )
+            } else {
+                None
+            };
+            match unchanged {
+                Some(v) => Status::Unchanged(v),
+                None => match build(workspace, name, &profile, extra_args, log.as_deref()) {
+                    Ok(v) => Status::Success(v),
+                    Err(e) => Status::Failed(e),
+                },
+            }
+        }
+        Err(e) => Status::Load(e),
+    };
+
+    Outcome {
+        name: name.to_string(),
+        status,
+        duration: start.elapsed(),
+        log,
+    }
+}
+
+/// Builds a profile into its own target directory, then copies the binaries
+/// listed in `[profile] bins` into the profile's dist directory alongside a
+/// build manifest. Returns the paths of the copied binaries.
+///
+/// Cargo's output goes to the terminal, or to `log` if given.
+pub fn build(
+    workspace: &Workspace,
+    name: &str,
+    profile: &Profile,
+    extra_args: &[String],
+    log: Option<&Path>,
+) -> Result<Vec<PathBuf>, Error> {
+    let target_dir = workspace.profile_target_dir(name);
+    let mut log = match log {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| Error::Log(path.into(), e))?;
+            }
+            Some(File::create(path).map_err(|e| Error::Log(path.into(), e))?)
+        }
+        None => None,
+    };
+
+    if let Some(arg) = profile
+        .cargo_args
+        .iter()
+        .chain(extra_args)
+        .find(|x| *x == "--message-format" || x.starts_with("--message-format="))
+    {
+        return Err(Error::MessageFormat(arg.to_string()));
+    }
+
+    let packages = if profile.bins.is_empty() {
+        HashMap::new()
+    } else {
+        packages(workspace)?
+    };
+
+    // Cargo prints artifact messages on stdout and still renders diagnostics
+    // to stderr as usual.
+    let args = std::iter::once("--message-format=json-render-diagnostics".to_string())
+        .chain(extra_args.iter().cloned())
+        .collect::<Vec<_>>();
+
+    let mut executables = vec![];
+    for mut cmd in profile.cargo_commands("build", &args) {
+        cmd.env("CARGO_TARGET_DIR", &target_dir)
+            .stdout(Stdio::piped());
+        if let Some(log) = log.as_ref() {
+            let stderr = log.try_clone().map_err(Error::Cargo)?;
+            cmd.stdin(Stdio::null()).stderr(stderr);
+        }
+
+        let mut child = cmd.spawn().map_err(Error::Cargo)?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        for line in stdout.lines() {
+            let line = line.map_err(Error::Cargo)?;
+            match serde_json::from_str::<serde_json::Value>(&line) {
+                Ok(msg) => executables.extend(executable(&msg)),
+                Err(_) => match log.as_mut() {
+                    Some(log) => writeln!(log, "{}", line).map_err(Error::Cargo)?,
+                    None => println!("{}", line),
+                },
+            }
+        }
+
+        let status = child.wait().map_err(Error::Cargo)?;
+        if !status.success() {
+            return Err(Error::Failed(status.code()));
+        }
+    }
+
+    let dist_dir = workspace.profile_dist_dir(name);
+    let mut out = vec![];
+    for bin in profile.bins.iter() {
+        let (id, bin, path) = find_executable(&executables, &packages, bin)
+            .ok_or_else(|| Error::MissingBinary(bin.to_string()))?;
+        let (package, version) = packages
+            .get(id)
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .unwrap_or_default();
+
+        let mut file_name = workspace
+            .artifact_name
+            .render([bin, package, name, version]);
+        if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+            file_name.push('.');
+            file_name.push_str(ext);
+        }
+
+        let dest = dist_dir.join(file_name);
+        std::fs::create_dir_all(&dist_dir)
+            .and_then(|_| std::fs::copy(path, &dest))
+            .map_err(|e| Error::Copy(path.clone(), dest.clone(), e))?;
+        out.push(dest);
+    }
+
+    let manifest_path = dist_dir.join(manifest::FILE_NAME);
+    let manifest = manifest::build_manifest(&workspace.root, name, profile, extra_args, &out)
+        .map_err(|e| Error::Manifest(manifest_path.clone(), e))?;
+    std::fs::create_dir_all(&dist_dir)
+        .and_then(|_| {
+            std::fs::write(
+                &manifest_path,
+                serde_json::to_string_pretty(&manifest).unwrap(),
+            )
+        })
+        .map_err(|e| Error::Manifest(manifest_path, e))?;
+
+    Ok(out)
+}
+
+/// The package id, target name and path of an executable produced by a
+/// `compiler-artifact` message.
+fn executable(msg: &serde_json::Value) -> Option<(String, String, PathBuf)> {
+    if msg.get("reason")?.as_str()? != "compiler-artifact" {
+        return None;
+    }
+    let path = msg.get("executable")?.as_str()?;
+    let name = msg.get("target")?.get("name")?.as_str()?;
+    let id = msg.get("package_id")?.as_str()?;
+    Some((id.to_string(), name.to_string(), PathBuf::from(path)))
+}
+
+/// The executable built for an entry of `[profile] bins`, either `bin` or
+/// `package/bin`.
+fn find_executable<'a>(
+    executables: &'a [(String, String, PathBuf)],
+    packages: &HashMap<String, (String, String)>,
+    bin: &str,
+) -> Option<&'a (String, String, PathBuf)> {
+    let (package, bin) = match bin.split_once('/') {
+        Some((p, b)) => (Some(p), b),
+        None => (None, bin),
+    };
+
+    executables.iter().find(|(id, b, _)| {
+        let in_package = match package {
+            Some(p) => packages.get(id).is_some_and(|x| x.0 == p),
+            None => true,
+        };
+        b == bin && in_package
+    })
+}
+
+/// Names and versions of the workspace's packages, by package id.
+fn packages(workspace: &Workspace) -> Result<HashMap<String, (String, String)>, Error> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let output = Command::new(cargo)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(&workspace.root)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(Error::Cargo)?;
+    if !output.status.success() {
+        return Err(Error::Metadata("cargo exited unsuccessfully".into()));
+    }
+
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| Error::Metadata(e.to_string()))?;
+    let packages = metadata
+        .get("packages")
+        .and_then(|x| x.as_array())
+        .ok_or_else(|| Error::Metadata("no packages listed".into()))?;
+
+    Ok(packages
+        .iter()
+        .filter_map(|x| {
+            Some((
+                x.get("id")?.as_str()?.to_string(),
+                (
+                    x.get("name")?.as_str()?.to_string(),
+                    x.get("version")?.as_str()?.to_string(),
+                ),
+            ))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn artifact_names() {
+        let name = "{package}_{bin}-{version}".parse::<ArtifactName>().unwrap();
+        assert_eq!(
+            name.render(["app", "demo", "dev", "1.0.0"]),
+            "demo_app-1.0.0"
+        );
+        assert_eq!(
+            ArtifactName::default().render(["app", "demo", "dev", "1.0.0"]),
+            "app-dev-1.0.0"
+        );
+
+        assert!("{bin".parse::<ArtifactName>().is_err());
+        assert!("{nope}".parse::<ArtifactName>().is_err());
+        assert!("out/{bin}".parse::<ArtifactName>().is_err());
+    }
+
+    #[test]
+    fn executables_from_messages() {
+        let msg = serde_json::json!({
+            "reason": "compiler-artifact",
+            "package_id": "demo 0.1.0",
+            "target": { "name": "app" },
+            "executable": "/target/debug/app",
+        });
+        assert_eq!(
+            executable(&msg),
+            Some((
+                "demo 0.1.0".to_string(),
+                "app".to_string(),
+                PathBuf::from("/target/debug/app")
+            ))
+        );
+
+        let lib = serde_json::json!({
+            "reason": "compiler-artifact",
+            "package_id": "demo 0.1.0",
+            "target": { "name": "demo" },
+            "executable": null,
+        });
+        assert_eq!(executable(&lib), None);
+        assert_eq!(
+            executable(&serde_json::json!({ "reason": "build-finished" })),
+            None
+        );
+    }
+
+    #[test]
+    fn executables_by_package() {
+        let executables = vec![
+            ("a 0.1.0".to_string(), "app".to_string(), PathBuf::from("a")),
+            ("b 0.1.0".to_string(), "app".to_string(), PathBuf::from("b")),
+        ];
+        let packages = [("a 0.1.0", "a"), ("b 0.1.0", "b")]
+            .into_iter()
+            .map(|(id, name)| (id.to_string(), (name.to_string(), "0.1.0".to_string())))
+            .collect::<HashMap<_, _>>();
+
+        let path = |bin| find_executable(&executables, &packages, bin).map(|x| &x.2);
+        assert_eq!(path("app"), Some(&PathBuf::from("a")));
+        assert_eq!(path("b/app"), Some(&PathBuf::from("b")));
+        assert_eq!(path("c/app"), None);
+        assert_eq!(path("other"), None);
+    }
+}
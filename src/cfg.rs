@@ -0,0 +1,414 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{activate, spec::Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("`{0}` contains whitespace and cannot be passed through RUSTFLAGS; use CARGO_ENCODED_RUSTFLAGS instead.")]
+    Whitespace(String),
+}
+
+/// A single `--cfg` passed to rustc: either a bare `name` or `name="value"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgFlag {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl CfgFlag {
+    /// Converts a profile value into a flag. `false` produces no flag, `true`
+    /// a bare name, and everything else a string value, as rustc only
+    /// accepts string literals as cfg values.
+    pub fn from_value(name: String, value: &Value) -> Option<CfgFlag> {
+        match value {
+            Value::Bool(false) => None,
+            Value::Bool(true) => Some(CfgFlag { name, value: None }),
+            v => Some(CfgFlag {
+                name,
+                value: Some(v.to_string()),
+            }),
+        }
+    }
+
+    /// The argument following `--cfg`, unquoted, for use with
+    /// [`std::process::Command::arg`].
+    pub fn to_rustc_arg(&self) -> String {
+        match &self.value {
+            Some(v) => format!("{}={:?}", self.name, v),
+            None => self.name.clone(),
+        }
+    }
+
+    /// A `cargo:rustc-cfg` directive for printing from a build script.
+    pub fn to_cargo_directive(&self) -> String {
+        format!("cargo:rustc-cfg={}", self.to_rustc_arg())
+    }
+}
+
+impl Display for CfgFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_rustc_arg())
+    }
+}
+
+/// `--cfg` arguments for each flag, unquoted.
+pub fn rustc_args(flags: &[CfgFlag]) -> Vec<String> {
+    flags
+        .iter()
+        .flat_map(|x| ["--cfg".to_string(), x.to_rustc_arg()])
+        .collect()
+}
+
+/// Whether a name is a plain ASCII identifier, as accepted for cfg names:
+/// a letter or `_` followed by letters, digits or `_`.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+/// Quotes an argument for a POSIX shell, if it needs quoting.
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=.,/:@+%".contains(c));
+    if is_safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Renders arguments for display as a shell command line.
+pub fn to_shell(args: &[String]) -> String {
+    args.iter()
+        .map(|x| shell_quote(x))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Renders arguments for the `RUSTFLAGS` environment variable, which cargo
+/// splits on whitespace without any quoting.
+pub fn to_rustflags(args: &[String]) -> Result<String, Error> {
+    if let Some(arg) = args.iter().find(|x| x.contains(char::is_whitespace)) {
+        return Err(Error::Whitespace(arg.to_string()));
+    }
+    Ok(args.join(" "))
+}
+
+/// Renders arguments for the `CARGO_ENCODED_RUSTFLAGS` environment variable,
+/// which separates arguments with `0x1f` and so can carry any value.
+pub fn to_encoded_rustflags(args: &[String]) -> String {
+    args.join("\x1f")
+}
+
+/// The rustc flags already set in the environment, preferring
+/// `CARGO_ENCODED_RUSTFLAGS` over `RUSTFLAGS` as cargo does, or `None` if
+/// neither is set.
+pub fn ambient_rustflags() -> Option<Vec<String>> {
+    if let Ok(x) = std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+        return Some(split_encoded_rustflags(&x));
+    }
+
+    std::env::var("RUSTFLAGS")
+        .ok()
+        .map(|x| x.split_whitespace().map(str::to_string).collect())
+}
+
+/// Splits a `CARGO_ENCODED_RUSTFLAGS` value into arguments.
+pub fn split_encoded_rustflags(s: &str) -> Vec<String> {
+    s.split('\x1f')
+        .filter(|x| !x.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Rustc flags set in Cargo config files, as cargo would apply them when
+/// run from `dir`: every matching `target.<triple>.rustflags` if there are
+/// any, and `build.rustflags` otherwise.
+///
+/// Blocks written by `cargo pbuild activate` are ignored. Only config files
+/// are read: `--config` arguments are not, and neither are
+/// `target.'cfg(..)'.rustflags`, which cannot be evaluated here. Such
+/// tables and any `CARGO_BUILD_RUSTFLAGS` or `CARGO_TARGET_<triple>_RUSTFLAGS`
+/// variable are returned as the second element so callers can report them.
+pub fn config_rustflags(dir: &Path, target: Option<&str>) -> (Vec<String>, Vec<String>) {
+    let home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".cargo")));
+    let configs = config_files(dir, home.as_deref())
+        .into_iter()
+        .filter_map(|path| {
+            let raw = std::fs::read_to_string(&path).ok()?;
+            let value = toml::from_str::<toml::Value>(&activate::strip_blocks(&raw)).ok()?;
+            Some((path, value))
+        })
+        .collect::<Vec<_>>();
+
+    let target = target.map(str::to_string).or_else(|| {
+        configs
+            .iter()
+            .rev()
+            .find_map(|(_, x)| x.get("build")?.get("target")?.as_str())
+            .map(str::to_string)
+            .or_else(host_triple)
+    });
+
+    let (flags, mut ignored) = resolve_rustflags(&configs, target.as_deref());
+    ignored.extend(
+        std::env::vars_os()
+            .filter_map(|(k, _)| k.into_string().ok())
+            .filter(|k| {
+                k == "CARGO_BUILD_RUSTFLAGS"
+                    || (k.starts_with("CARGO_TARGET_") && k.ends_with("_RUSTFLAGS"))
+            })
+            .map(|k| format!("`{}`", k)),
+    );
+    (flags, ignored)
+}
+
+/// Picks the rustflags cargo would use from parsed config files, lowest
+/// precedence first. Flags from several files are concatenated in that
+/// order, as cargo merges arrays.
+fn resolve_rustflags(
+    configs: &[(PathBuf, toml::Value)],
+    target: Option<&str>,
+) -> (Vec<String>, Vec<String>) {
+    let mut build_flags = vec![];
+    let mut target_flags = vec![];
+    let mut unevaluated = vec![];
+    for (path, config) in configs.iter() {
+        if let Some(flags) = config.get("build").and_then(|x| x.get("rustflags")) {
+            build_flags.extend(rustflags_value(flags));
+        }
+        let targets = config.get("target").and_then(|x| x.as_table());
+        for (key, table) in targets.into_iter().flatten() {
+            let flags = match table.get("rustflags") {
+                Some(v) => rustflags_value(v),
+                None => continue,
+            };
+            if key.starts_with("cfg(") {
+                unevaluated.push(format!(
+                    "`target.'{}'.rustflags` in `{}`",
+                    key,
+                    path.display()
+                ));
+            } else if Some(key.as_str()) == target {
+                target_flags.extend(flags);
+            }
+        }
+    }
+
+    if target_flags.is_empty() {
+        (build_flags, unevaluated)
+    } else {
+        (target_flags, unevaluated)
+    }
+}
+
+/// Cargo config files that apply in `dir`, lowest precedence first: the one
+/// in `home` (`CARGO_HOME`), then those in `dir` and its ancestors from the
+/// outermost in. A file reached both ways keeps its place among the
+/// ancestors, as cargo reads it only once.
+fn config_files(dir: &Path, home: Option<&Path>) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    dirs.extend(home.map(Path::to_path_buf));
+    let mut ancestors = dir
+        .ancestors()
+        .map(|x| x.join(".cargo"))
+        .collect::<Vec<_>>();
+    ancestors.reverse();
+    dirs.extend(ancestors);
+
+    let mut out: Vec<PathBuf> = vec![];
+    for dir in dirs {
+        let path = ["config.toml", "config"]
+            .iter()
+            .map(|x| dir.join(x))
+            .find(|x| x.is_file());
+        if let Some(path) = path {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            out.retain(|x| x.canonicalize().ok().as_ref() != Some(&canonical));
+            out.push(path);
+        }
+    }
+    out
+}
+
+fn rustflags_value(value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(s) => s.split_whitespace().map(str::to_string).collect(),
+        toml::Value::Array(x) => x
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(str::to_string)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The host target triple reported by `rustc -vV`.
+fn host_triple() -> Option<String> {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let output = std::process::Command::new(rustc).arg("-vV").output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|x| x.strip_prefix("host: "))
+        .map(|x| x.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(name: &str, value: Option<&str>) -> CfgFlag {
+        CfgFlag {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn flags_render_for_rustc_and_build_scripts() {
+        let flags = [flag("pb_board", Some("nrf52-dk")), flag("pb_radio", None)];
+        assert_eq!(
+            rustc_args(&flags),
+            ["--cfg", "pb_board=\"nrf52-dk\"", "--cfg", "pb_radio"]
+        );
+        assert_eq!(
+            flags[0].to_cargo_directive(),
+            "cargo:rustc-cfg=pb_board=\"nrf52-dk\""
+        );
+        assert_eq!(flags[1].to_cargo_directive(), "cargo:rustc-cfg=pb_radio");
+    }
+
+    #[test]
+    fn values_are_escaped_inside_the_cfg_string() {
+        let arg = flag("pb_name", Some("a \"b\"")).to_rustc_arg();
+        assert_eq!(arg, "pb_name=\"a \\\"b\\\"\"");
+    }
+
+    #[test]
+    fn shell_quotes_key_value_flags() {
+        let args = rustc_args(&[flag("pb_board", Some("dk")), flag("pb_name", Some("it's"))]);
+        assert_eq!(
+            to_shell(&args),
+            "--cfg 'pb_board=\"dk\"' --cfg 'pb_name=\"it'\\''s\"'"
+        );
+        assert_eq!(to_shell(&["-Copt-level=3".to_string()]), "-Copt-level=3");
+        assert_eq!(to_shell(&[String::new()]), "''");
+    }
+
+    #[test]
+    fn rustflags_reject_whitespace() {
+        let args = rustc_args(&[flag("pb_board", Some("dk"))]);
+        assert_eq!(to_rustflags(&args).unwrap(), "--cfg pb_board=\"dk\"");
+
+        let args = rustc_args(&[flag("pb_name", Some("a b"))]);
+        match to_rustflags(&args) {
+            Err(Error::Whitespace(arg)) => assert_eq!(arg, "pb_name=\"a b\""),
+            other => panic!("expected a whitespace error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encoded_rustflags_join_with_unit_separator() {
+        let args = rustc_args(&[flag("pb_name", Some("a b"))]);
+        let encoded = to_encoded_rustflags(&args);
+        assert_eq!(encoded, "--cfg\x1fpb_name=\"a b\"");
+        assert_eq!(split_encoded_rustflags(&encoded), args);
+    }
+
+    /// Creates a fresh directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pbuild-cfg-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn write_config(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        std::fs::create_dir_all(dir.join(".cargo")).unwrap();
+        let path = dir.join(".cargo").join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn config(s: &str) -> (PathBuf, toml::Value) {
+        (PathBuf::from("config.toml"), toml::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn config_files_put_home_first_and_nearest_last() {
+        let root = temp_dir("files");
+        let inner = root.join("ws").join("crate");
+        std::fs::create_dir_all(&inner).unwrap();
+        let home = root.join("home");
+        let home_config = write_config(&home, "config.toml", "");
+        let outer = write_config(&root, "config.toml", "");
+        let nearest = write_config(&inner, "config", "");
+
+        assert_eq!(
+            config_files(&inner, Some(&home.join(".cargo"))),
+            [home_config, outer, nearest]
+        );
+    }
+
+    #[test]
+    fn config_files_read_home_once_when_it_is_an_ancestor() {
+        let root = temp_dir("home-ancestor");
+        let inner = root.join("ws");
+        std::fs::create_dir_all(&inner).unwrap();
+        let home = write_config(&root, "config.toml", "");
+        let nearest = write_config(&inner, "config.toml", "");
+
+        assert_eq!(
+            config_files(&inner, Some(&root.join(".cargo"))),
+            [home, nearest]
+        );
+    }
+
+    #[test]
+    fn build_rustflags_are_concatenated_in_precedence_order() {
+        let configs = [
+            config("[build]\nrustflags = \"-C a\"\n"),
+            config("[build]\nrustflags = [\"-C\", \"b\"]\n"),
+        ];
+        let (flags, ignored) = resolve_rustflags(&configs, Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(flags, ["-C", "a", "-C", "b"]);
+        assert!(ignored.is_empty());
+    }
+
+    #[test]
+    fn target_rustflags_replace_build_rustflags() {
+        let configs = [
+            config("[target.x86_64-unknown-linux-gnu]\nrustflags = [\"-C\", \"t\"]\n"),
+            config(
+                "[build]\nrustflags = [\"-C\", \"b\"]\n\
+                 [target.thumbv7em-none-eabihf]\nrustflags = [\"-C\", \"other\"]\n",
+            ),
+        ];
+        let (flags, _) = resolve_rustflags(&configs, Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(flags, ["-C", "t"]);
+
+        let (flags, _) = resolve_rustflags(&configs, Some("aarch64-unknown-linux-gnu"));
+        assert_eq!(flags, ["-C", "b"]);
+    }
+
+    #[test]
+    fn cfg_target_rustflags_are_reported_and_not_applied() {
+        let configs = [config(
+            "[build]\nrustflags = [\"-C\", \"b\"]\n\
+             [target.'cfg(unix)']\nrustflags = [\"-C\", \"unix\"]\n",
+        )];
+        let (flags, ignored) = resolve_rustflags(&configs, Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(flags, ["-C", "b"]);
+        assert_eq!(ignored, ["`target.'cfg(unix)'.rustflags` in `config.toml`"]);
+    }
+}
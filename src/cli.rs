@@ -1,9 +1,22 @@
-use std::process::exit;
+use std::{path::PathBuf, process::exit, str::FromStr};
 
 use gumdrop::Options;
-use indexmap::IndexMap;
 
-use crate::{profile::Profile, spec::Spec};
+use crate::{
+    activate::{self, Targets},
+    build, diff, docs,
+    graph::{self, Graph},
+    lint::{self, Level, Lint},
+    manifest,
+    matrix::{self, Matrix},
+    profile::{Profile, DEFAULT_SPEC},
+    schema,
+    select::{self, Selector},
+    shell::Shell,
+    spec::Spec,
+    wizard,
+    workspace::{LoadError, ProfileSource, Workspace},
+};
 
 #[derive(Debug, Options)]
 struct Args {
@@ -22,9 +35,281 @@ struct InfoArgs {
     profile: Option<String>,
 }
 
+#[derive(Debug, Options)]
+struct BuildArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(free, help = "profile to build")]
+    profile: Option<String>,
+
+    #[options(free, help = "extra arguments passed to cargo")]
+    args: Vec<String>,
+
+    #[options(help = "build every profile")]
+    all: bool,
+
+    #[options(
+        meta = "SELECTOR",
+        help = "build profiles matching a name glob or `tag:<TAG>` (repeatable)"
+    )]
+    filter: Vec<Selector>,
+
+    #[options(
+        meta = "N",
+        help = "number of profiles to build at once (with --all or --filter)",
+        default = "1"
+    )]
+    jobs: usize,
+
+    #[options(
+        no_short,
+        help = "skip profiles whose fingerprint and sources are unchanged since their last build"
+    )]
+    skip_unchanged: bool,
+}
+
+#[derive(Debug, Options)]
+struct ValidateArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(help = "validate every spec and profile (the default)")]
+    all: bool,
+
+    #[options(help = "only validate the given spec")]
+    spec: Option<String>,
+
+    #[options(help = "only validate the given profile")]
+    profile: Option<String>,
+
+    #[options(short = "A", meta = "LINT", help = "allow a lint")]
+    allow: Vec<String>,
+
+    #[options(short = "W", meta = "LINT", help = "warn on a lint")]
+    warn: Vec<String>,
+
+    #[options(short = "D", meta = "LINT", help = "deny a lint")]
+    deny: Vec<String>,
+
+    #[options(no_short, help = "treat all warnings as errors")]
+    deny_warnings: bool,
+}
+
+#[derive(Debug, Options)]
+struct ListArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(
+        no_short,
+        long = "type",
+        help = "only show entries with the given type"
+    )]
+    ty: Option<String>,
+
+    #[options(
+        no_short,
+        help = "only show entries enabling the given field (type.field)"
+    )]
+    enabling: Option<String>,
+
+    #[options(
+        meta = "SELECTOR",
+        help = "only show profiles matching a name glob or `tag:<TAG>` (repeatable)"
+    )]
+    filter: Vec<Selector>,
+
+    #[options(help = "output format (text or json)", default = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Options)]
+struct EnvArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(free, help = "profile to export")]
+    profile: Option<String>,
+
+    #[options(
+        help = "syntax to print (sh, fish, powershell or dotenv)",
+        default = "sh"
+    )]
+    shell: Shell,
+}
+
+#[derive(Debug, Options)]
+struct ActivateArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(free, help = "profile to activate")]
+    profile: Option<String>,
+
+    #[options(no_short, help = "do not write .cargo/config.toml")]
+    no_cargo_config: bool,
+
+    #[options(no_short, help = "do not write .vscode/settings.json")]
+    no_vscode: bool,
+}
+
+#[derive(Debug, Options)]
+struct DiffArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(free, help = "profiles to compare")]
+    profiles: Vec<String>,
+
+    #[options(help = "output format (text or json)", default = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Options)]
+struct MatrixArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(help = "only show the given spec")]
+    spec: Option<String>,
+
+    #[options(
+        meta = "SELECTOR",
+        help = "only show profiles matching a name glob or `tag:<TAG>` (repeatable)"
+    )]
+    filter: Vec<Selector>,
+
+    #[options(help = "output format (text, markdown, csv or html)", default = "text")]
+    format: matrix::Format,
+}
+
+#[derive(Debug, Options)]
+struct DocsArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(help = "only document the given spec")]
+    spec: Option<String>,
+
+    #[options(help = "output format (markdown or html)", default = "markdown")]
+    format: docs::Format,
+
+    #[options(
+        meta = "DIR",
+        help = "write one file per spec into a directory instead of printing"
+    )]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Options)]
+struct GraphArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(help = "the spec to graph, if not the profile's")]
+    spec: Option<String>,
+
+    #[options(help = "highlight the fields enabled by a profile")]
+    profile: Option<String>,
+
+    #[options(help = "output format (dot or mermaid)", default = "dot")]
+    format: graph::Format,
+}
+
+#[derive(Debug, Options)]
+struct SchemaArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(help = "the spec to describe (default: main)")]
+    spec: Option<String>,
+
+    #[options(meta = "FILE", help = "write the schema to a file instead of printing")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Options)]
+struct NewArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(free, help = "name of the profile to create")]
+    name: Option<String>,
+
+    #[options(help = "the spec the profile uses (default: main)")]
+    spec: Option<String>,
+
+    #[options(no_short, help = "overwrite an existing profile")]
+    force: bool,
+}
+
+#[derive(Debug, Options)]
+struct UseArgs {
+    #[options(help = "show help information")]
+    help: bool,
+
+    #[options(free, help = "profile to use by default")]
+    profile: Option<String>,
+
+    #[options(no_short, help = "clear the selected profile")]
+    clear: bool,
+}
+
+#[derive(Debug, Options)]
+struct DeactivateArgs {
+    #[options(help = "show help information")]
+    help: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown format `{}`, expected `text` or `json`", s)),
+        }
+    }
+}
+
 #[derive(Debug, Options)]
 enum Command {
+    #[options(help = "show info about a profile or spec")]
     Info(InfoArgs),
+    #[options(help = "build a profile")]
+    Build(BuildArgs),
+    #[options(help = "check specs and profiles for errors")]
+    Validate(ValidateArgs),
+    #[options(help = "list profiles and specs")]
+    List(ListArgs),
+    #[options(help = "print a profile as shell environment variables")]
+    Env(EnvArgs),
+    #[options(help = "apply a profile to .cargo/config.toml and editor settings")]
+    Activate(ActivateArgs),
+    #[options(help = "undo `activate`")]
+    Deactivate(DeactivateArgs),
+    #[options(help = "select the profile used when none is given")]
+    Use(UseArgs),
+    #[options(help = "compare two profiles")]
+    Diff(DiffArgs),
+    #[options(help = "show which profiles enable which fields")]
+    Matrix(MatrixArgs),
+    #[options(help = "render documentation for specs")]
+    Docs(DocsArgs),
+    #[options(help = "show a spec's fields and dependencies as a graph")]
+    Graph(GraphArgs),
+    #[options(help = "print a JSON Schema for profiles using a spec")]
+    Schema(SchemaArgs),
+    #[options(help = "create a profile interactively")]
+    New(NewArgs),
 }
 
 impl Args {
@@ -37,70 +322,765 @@ impl Args {
 
 impl InfoArgs {
     fn print_usage() {
-        println!("cargo-pbuild info -- Show info about a profile or spec\n\nUsage: cargo pbuild info [OPTIONS]\n");
+        println!("cargo-pbuild info -- Show info about a profile or spec\n\nUsage: cargo pbuild info [OPTIONS]\n\nWithout options, shows the current profile.\n");
         println!("{}\n", InfoArgs::usage());
     }
 }
 
+impl BuildArgs {
+    fn print_usage() {
+        println!("cargo-pbuild build -- Build a profile\n\nUsage: cargo pbuild build [OPTIONS] [PROFILE] [-- CARGO_ARGS]\n       cargo pbuild build [OPTIONS] --all|--filter <SELECTOR>... [-- CARGO_ARGS]\n\nEach profile builds into its own target directory, target/pbuild/<PROFILE>/,\nand its binaries are copied to dist/<PROFILE>/.\n");
+        println!("{}\n", BuildArgs::usage());
+    }
+}
+
+impl ValidateArgs {
+    fn print_usage() {
+        println!("cargo-pbuild validate -- Check specs and profiles for errors\n\nUsage: cargo pbuild validate [OPTIONS]\n");
+        println!("{}\n", ValidateArgs::usage());
+        println!("Available lints:");
+        for lint in Lint::ALL {
+            println!("  {} (default: {})", lint, lint.default_level());
+        }
+    }
+}
+
+impl ListArgs {
+    fn print_usage() {
+        println!(
+            "cargo-pbuild list -- List profiles and specs\n\nUsage: cargo pbuild list [OPTIONS]\n"
+        );
+        println!("{}\n", ListArgs::usage());
+    }
+}
+
+impl EnvArgs {
+    fn print_usage() {
+        println!("cargo-pbuild env -- Print a profile as shell environment variables\n\nUsage: cargo pbuild env [OPTIONS] [PROFILE]\n\nExample: eval \"$(cargo pbuild env dev)\"\n\nPBUILD_PROFILE is not exported, so `cargo pbuild use` keeps working afterwards.\n");
+        println!("{}\n", EnvArgs::usage());
+    }
+}
+
+impl ActivateArgs {
+    fn print_usage() {
+        println!("cargo-pbuild activate -- Apply a profile to .cargo/config.toml and editor settings\n\nUsage: cargo pbuild activate [OPTIONS] [PROFILE]\n");
+        println!("{}\n", ActivateArgs::usage());
+    }
+}
+
+impl DiffArgs {
+    fn print_usage() {
+        println!("cargo-pbuild diff -- Compare two profiles\n\nUsage: cargo pbuild diff [OPTIONS] <PROFILE_A> <PROFILE_B>\n");
+        println!("{}\n", DiffArgs::usage());
+    }
+}
+
+impl MatrixArgs {
+    fn print_usage() {
+        println!("cargo-pbuild matrix -- Show which profiles enable which fields\n\nUsage: cargo pbuild matrix [OPTIONS]\n");
+        println!("{}\n", MatrixArgs::usage());
+    }
+}
+
+impl DocsArgs {
+    fn print_usage() {
+        println!("cargo-pbuild docs -- Render documentation for specs\n\nUsage: cargo pbuild docs [OPTIONS]\n");
+        println!("{}\n", DocsArgs::usage());
+    }
+}
+
+impl GraphArgs {
+    fn print_usage() {
+        println!("cargo-pbuild graph -- Show a spec's fields and dependencies as a graph\n\nUsage: cargo pbuild graph [OPTIONS]\n");
+        println!("{}\n", GraphArgs::usage());
+    }
+}
+
+impl SchemaArgs {
+    fn print_usage() {
+        println!("cargo-pbuild schema -- Print a JSON Schema for profiles using a spec\n\nUsage: cargo pbuild schema [OPTIONS]\n\nPoint an editor at the schema, e.g. with a `#:schema ./profile.schema.json`\ncomment at the top of a profile for taplo.\n");
+        println!("{}\n", SchemaArgs::usage());
+    }
+}
+
+impl NewArgs {
+    fn print_usage() {
+        println!("cargo-pbuild new -- Create a profile interactively\n\nUsage: cargo pbuild new [OPTIONS] <NAME>\n");
+        println!("{}\n", NewArgs::usage());
+    }
+}
+
+impl UseArgs {
+    fn print_usage() {
+        println!("cargo-pbuild use -- Select the profile used when none is given\n\nUsage: cargo pbuild use [OPTIONS] [PROFILE]\n\nWithout a profile, shows the current selection. The PBUILD_PROFILE environment\nvariable takes precedence over the selection, which takes precedence over\n`default-profile` in the manifest metadata.\n");
+        println!("{}\n", UseArgs::usage());
+    }
+}
+
+impl DeactivateArgs {
+    fn print_usage() {
+        println!("cargo-pbuild deactivate -- Undo `cargo pbuild activate`\n\nUsage: cargo pbuild deactivate\n");
+        println!("{}\n", DeactivateArgs::usage());
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Error loading data.")]
     Load(#[from] LoadError),
+
+    #[error("Error locating workspace.")]
+    Workspace(#[from] crate::workspace::Error),
+
+    #[error("Build failed.")]
+    Build(#[from] build::Error),
+
+    #[error("Could not generate compiler flags.")]
+    Cfg(#[from] crate::cfg::Error),
+
+    #[error("Could not export the profile's environment.")]
+    Shell(#[from] crate::shell::Error),
+
+    #[error("Could not write `{0}`.")]
+    Write(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not read answers.")]
+    Prompt(#[source] std::io::Error),
+
+    #[error("Could not access local state.")]
+    State(#[from] crate::state::Error),
+
+    #[error("Could not update project settings.")]
+    Activate(#[from] activate::Error),
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum LoadError {
-    #[error("IO error")]
-    Io(#[from] std::io::Error),
-
-    #[error("No main spec found")]
-    MissingMainSpec,
-
-    #[error("Spec error")]
-    Spec(#[from] crate::spec::Error),
-
-    #[error("Profile error")]
-    Profile(#[from] crate::profile::Error),
-}
-
-fn load_data() -> Result<(IndexMap<String, Spec>, IndexMap<String, Profile>), LoadError> {
-    let mut specs = IndexMap::new();
-    for item in std::fs::read_dir("./profiles/specs")?.filter_map(Result::ok) {
-        let p = item.path();
-        if p.extension().and_then(|x| x.to_str()) == Some("toml") {
-            let spec = Spec::parse_path(&p)?;
-            specs.insert(
-                p.file_stem()
-                    .and_then(|x| x.to_str())
-                    .map(|x| x.to_string())
-                    .unwrap(),
-                spec,
-            );
+fn load_spec(workspace: &Workspace, name: &str) -> Result<Spec, Error> {
+    match workspace.load_spec(name) {
+        Err(LoadError::SpecNotFound(_)) => {
+            eprintln!("No spec found with the name `{}`.", name);
+            exit(1);
+        }
+        result => Ok(result?),
+    }
+}
+
+fn load_profile(workspace: &Workspace, name: &str) -> Result<Profile, Error> {
+    match workspace.load_profile(name) {
+        Err(LoadError::ProfileNotFound(_)) => {
+            eprintln!("No profile found with the name `{}`.", name);
+            exit(1);
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Falls back to the current profile when none was given on the command line.
+fn resolve_profile(workspace: &Workspace, profile: Option<String>) -> Result<String, Error> {
+    if let Some(v) = profile {
+        return Ok(v);
+    }
+
+    match workspace.current_profile()? {
+        Some((v, _)) => Ok(v),
+        None => {
+            eprintln!("No profile given, and no default profile is set.\nSelect one with `cargo pbuild use <PROFILE>` or set `default-profile` in [package.metadata.pbuild].");
+            exit(2);
+        }
+    }
+}
+
+fn format_error(error: &dyn std::error::Error) -> String {
+    let mut out = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        out.push_str("\n    caused by: ");
+        out.push_str(&e.to_string());
+        source = e.source();
+    }
+    out
+}
+
+fn validate(workspace: &Workspace, args: ValidateArgs) -> Result<(), Error> {
+    let mut levels = workspace.lints.clone();
+    for (names, level) in [
+        (&args.allow, Level::Allow),
+        (&args.warn, Level::Warn),
+        (&args.deny, Level::Deny),
+    ] {
+        for name in names.iter() {
+            match name.parse::<Lint>() {
+                Ok(lint) => levels.set(lint, level),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    exit(2);
+                }
+            }
+        }
+    }
+    if args.deny_warnings {
+        levels.deny_warnings();
+    }
+
+    let all = args.all || (args.spec.is_none() && args.profile.is_none());
+    let (spec_names, profile_names) = if all {
+        (workspace.spec_names()?, workspace.profile_names()?)
+    } else {
+        (
+            args.spec.into_iter().collect::<Vec<_>>(),
+            args.profile.into_iter().collect::<Vec<_>>(),
+        )
+    };
+
+    let mut errors = 0;
+    let mut warnings = 0;
+    let mut diagnostics = vec![];
+
+    let mut specs = vec![];
+    for name in spec_names.iter() {
+        match workspace.load_spec(name) {
+            Ok(spec) => {
+                diagnostics.extend(lint::check_spec(name, &spec));
+                specs.push((name, spec));
+            }
+            Err(e) => {
+                errors += 1;
+                println!(
+                    "error: spec `{}` failed to load: {}",
+                    name,
+                    format_error(&e)
+                );
+            }
+        }
+    }
+
+    let mut profiles = vec![];
+    for name in profile_names.iter() {
+        match workspace.load_profile(name) {
+            Ok(profile) => {
+                diagnostics.extend(lint::check_profile(name, &profile));
+                profiles.push((name.as_str(), profile));
+            }
+            Err(e) => {
+                errors += 1;
+                println!(
+                    "error: profile `{}` failed to load: {}",
+                    name,
+                    format_error(&e)
+                );
+            }
+        }
+    }
+
+    // Unused fields can only be judged when every profile has been seen.
+    if all {
+        for (name, spec) in specs.iter() {
+            let users = profiles
+                .iter()
+                .map(|(_, x)| x)
+                .filter(|x| &x.spec_name == *name)
+                .collect::<Vec<_>>();
+            if !users.is_empty() {
+                diagnostics.extend(lint::check_unused_fields(name, spec, &users));
+            }
+        }
+
+        let profiles = profiles.iter().map(|(n, p)| (*n, p)).collect::<Vec<_>>();
+        diagnostics.extend(lint::check_duplicate_profiles(&profiles));
+    }
+
+    for diagnostic in diagnostics {
+        let level = match levels.get(diagnostic.lint) {
+            Level::Allow => continue,
+            Level::Warn => {
+                warnings += 1;
+                "warning"
+            }
+            Level::Deny => {
+                errors += 1;
+                "error"
+            }
+        };
+        println!(
+            "{}[{}]: {}: {}",
+            level, diagnostic.lint, diagnostic.origin, diagnostic.message
+        );
+    }
+
+    println!(
+        "\nChecked {} spec(s) and {} profile(s): {} error(s), {} warning(s).",
+        spec_names.len(),
+        profile_names.len(),
+        errors,
+        warnings
+    );
+
+    if errors > 0 {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+fn build_many(
+    workspace: &Workspace,
+    filters: &[Selector],
+    args: &[String],
+    jobs: usize,
+    skip_unchanged: bool,
+) -> Result<(), Error> {
+    let names = select::select_profiles(workspace, filters)?;
+    if names.is_empty() {
+        eprintln!("No profiles matched.");
+        exit(1);
+    }
+
+    let outcomes = build::build_profiles(workspace, &names, args, jobs, skip_unchanged);
+
+    let width = names.iter().map(|x| x.len()).max().unwrap_or(0).max(7);
+    println!("\n{:<width$}  {:<6}  {:>8}", "Profile", "Result", "Time");
+    for outcome in outcomes.iter() {
+        let result = match outcome.status {
+            build::Status::Unchanged(_) => "skip",
+            _ if outcome.status.is_success() => "ok",
+            _ => "FAILED",
+        };
+        print!(
+            "{:<width$}  {:<6}  {:>7.1}s",
+            outcome.name,
+            result,
+            outcome.duration.as_secs_f64()
+        );
+        match &outcome.status {
+            build::Status::Success(paths) if !paths.is_empty() => print!(
+                "  {} binary(ies) in {}",
+                paths.len(),
+                workspace.profile_dist_dir(&outcome.name).display()
+            ),
+            build::Status::Success(_) => {}
+            build::Status::Unchanged(_) => print!("  unchanged"),
+            build::Status::Failed(build::Error::Failed(code)) => {
+                match code {
+                    Some(code) => print!("  cargo exited with code {}", code),
+                    None => print!("  cargo was terminated by a signal"),
+                }
+                if let Some(log) = outcome.log.as_ref() {
+                    print!(" (log: {})", log.display());
+                }
+            }
+            build::Status::Failed(_) => print!("  build failed"),
+            build::Status::Load(_) => print!("  could not load profile"),
+        }
+        println!();
+    }
+
+    for outcome in outcomes.iter() {
+        match &outcome.status {
+            build::Status::Load(e) => eprintln!("\nerror: {}", format_error(e)),
+            build::Status::Failed(build::Error::Failed(_)) => {}
+            build::Status::Failed(e) => {
+                eprintln!("\nerror: profile `{}`: {}", outcome.name, format_error(e))
+            }
+            _ => {}
+        }
+    }
+
+    let failed = outcomes.iter().filter(|x| !x.status.is_success()).count();
+    let skipped = outcomes
+        .iter()
+        .filter(|x| matches!(x.status, build::Status::Unchanged(_)))
+        .count();
+    println!(
+        "\n{} profile(s) built, {} unchanged, {} failed.",
+        outcomes.len() - failed - skipped,
+        skipped,
+        failed
+    );
+    if failed > 0 {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+fn diff(workspace: &Workspace, args: DiffArgs) -> Result<(), Error> {
+    let (a_name, b_name) = match args.profiles.as_slice() {
+        [a, b] => (a, b),
+        _ => {
+            eprintln!("Expected exactly two profiles to compare.");
+            exit(2);
+        }
+    };
+
+    let a = load_profile(workspace, a_name)?;
+    let b = load_profile(workspace, b_name)?;
+    let differences = diff::diff(&a, &b);
+
+    match args.format {
+        OutputFormat::Text => {
+            if differences.is_empty() {
+                println!(
+                    "Profiles `{}` and `{}` build the same thing; only their descriptions and tags may differ.",
+                    a_name, b_name
+                );
+                return Ok(());
+            }
+
+            println!("--- {}\n+++ {}", a_name, b_name);
+            let mut section = "";
+            for difference in differences.iter() {
+                if difference.section != section {
+                    section = difference.section;
+                    println!("\n{}:", section);
+                }
+                println!("  {}", difference);
+            }
+        }
+        OutputFormat::Json => {
+            let out = serde_json::json!({
+                "a": a_name,
+                "b": b_name,
+                "differences": differences.iter().map(|x| x.to_json()).collect::<Vec<_>>(),
+            });
+            println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        }
+    }
+
+    Ok(())
+}
+
+fn matrix(workspace: &Workspace, args: MatrixArgs) -> Result<(), Error> {
+    let mut profiles = vec![];
+    for name in select::select_profiles(workspace, &args.filter)? {
+        match workspace.load_profile(&name) {
+            Ok(profile) => profiles.push((name, profile)),
+            Err(e) => eprintln!("warning: skipping profile: {}", format_error(&e)),
+        }
+    }
+
+    let spec_names = match args.spec {
+        Some(v) => vec![v],
+        None => {
+            let mut names = profiles
+                .iter()
+                .map(|(_, p)| p.spec_name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            names
         }
+    };
+
+    if args.format == matrix::Format::Csv && spec_names.len() > 1 {
+        eprintln!("Profiles use more than one spec; choose one with `--spec` for CSV output.");
+        exit(2);
+    }
+
+    let mut matrices = vec![];
+    for spec_name in spec_names.iter() {
+        let spec = load_spec(workspace, spec_name)?;
+        let columns = profiles
+            .iter()
+            .filter(|(_, p)| &p.spec_name == spec_name)
+            .map(|(n, p)| (n.as_str(), p))
+            .collect::<Vec<_>>();
+        matrices.push(Matrix::new(spec_name, &spec, &columns));
     }
 
-    let main_spec = match specs.get("main") {
-        Some(v) => v,
-        None => return Err(LoadError::MissingMainSpec),
+    print!("{}", matrix::render_all(&matrices, args.format));
+    Ok(())
+}
+
+fn docs(workspace: &Workspace, args: DocsArgs) -> Result<(), Error> {
+    let spec_names = match args.spec {
+        Some(v) => vec![v],
+        None => workspace.spec_names()?,
     };
 
-    let mut profiles = IndexMap::new();
-
-    for item in std::fs::read_dir("./profiles")?.filter_map(Result::ok) {
-        let p = item.path();
-        if p.extension().and_then(|x| x.to_str()) == Some("toml") {
-            let profile = Profile::parse_path(main_spec, &p)?;
-            profiles.insert(
-                p.file_stem()
-                    .and_then(|x| x.to_str())
-                    .map(|x| x.to_string())
-                    .unwrap(),
-                profile,
+    let mut profiles = vec![];
+    for name in workspace.profile_names()? {
+        match workspace.load_profile(&name) {
+            Ok(profile) => profiles.push((name, profile)),
+            Err(e) => eprintln!("warning: skipping profile: {}", format_error(&e)),
+        }
+    }
+
+    let mut specs = vec![];
+    for spec_name in spec_names.iter() {
+        specs.push((spec_name.as_str(), load_spec(workspace, spec_name)?));
+    }
+    let docs = specs
+        .iter()
+        .map(|(spec_name, spec)| {
+            let users = profiles
+                .iter()
+                .filter(|(_, p)| &p.spec_name == spec_name)
+                .map(|(n, p)| (n.as_str(), p))
+                .collect::<Vec<_>>();
+            (*spec_name, spec, users)
+        })
+        .collect::<Vec<_>>();
+
+    match args.output.as_ref() {
+        Some(dir) => {
+            for spec_docs in docs {
+                let path = dir.join(format!("{}.{}", spec_docs.0, args.format.extension()));
+                let out = docs::render(&[spec_docs], args.format);
+                std::fs::create_dir_all(dir)
+                    .and_then(|_| std::fs::write(&path, out))
+                    .map_err(|e| Error::Write(path.clone(), e))?;
+                println!("Wrote {}", path.display());
+            }
+        }
+        None => print!("{}", docs::render(&docs, args.format)),
+    }
+
+    Ok(())
+}
+
+fn graph(workspace: &Workspace, args: GraphArgs) -> Result<(), Error> {
+    let profile = match args.profile.as_ref() {
+        Some(name) => Some(load_profile(workspace, name)?),
+        None => None,
+    };
+
+    let spec_name = match (args.spec, profile.as_ref()) {
+        (Some(spec), Some(profile)) if spec != profile.spec_name => {
+            eprintln!(
+                "Profile `{}` uses spec `{}`, not `{}`.",
+                args.profile.unwrap_or_default(),
+                profile.spec_name,
+                spec
             );
+            exit(2);
+        }
+        (Some(spec), _) => spec,
+        (None, Some(profile)) => profile.spec_name.clone(),
+        (None, None) => match workspace.spec_names()?.as_slice() {
+            [name] => name.clone(),
+            _ => {
+                eprintln!("More than one spec found; choose one with `--spec` or `--profile`.");
+                exit(2);
+            }
+        },
+    };
+
+    let spec = match profile.as_ref() {
+        Some(profile) => profile.spec.clone(),
+        None => load_spec(workspace, &spec_name)?,
+    };
+    let graph = Graph::new(&spec_name, &spec, profile.as_ref());
+    print!("{}", graph.render(args.format));
+    Ok(())
+}
+
+fn schema(workspace: &Workspace, args: SchemaArgs) -> Result<(), Error> {
+    let spec_name = args.spec.unwrap_or_else(|| DEFAULT_SPEC.to_string());
+    let spec = load_spec(workspace, &spec_name)?;
+    let out = serde_json::to_string_pretty(&schema::schema(&spec_name, &spec)).unwrap();
+
+    match args.output {
+        Some(path) => {
+            std::fs::write(&path, out + "\n").map_err(|e| Error::Write(path.clone(), e))?;
+            println!("Wrote {}", path.display());
+        }
+        None => println!("{}", out),
+    }
+    Ok(())
+}
+
+fn new_profile(workspace: &Workspace, name: &str, args: NewArgs) -> Result<(), Error> {
+    let path = workspace.profile_path(name);
+    if path.exists() && !args.force {
+        eprintln!(
+            "Profile `{}` already exists; pass `--force` to overwrite it.",
+            name
+        );
+        exit(1);
+    }
+
+    let spec_name = args.spec.unwrap_or_else(|| DEFAULT_SPEC.to_string());
+    let spec = load_spec(workspace, &spec_name)?;
+    println!("Creating profile `{}` using spec `{}`.\n", name, spec_name);
+
+    let stdin = std::io::stdin();
+    let out = wizard::new_profile(&spec_name, &spec, stdin.lock(), std::io::stdout())
+        .map_err(Error::Prompt)?;
+
+    // The wizard only offers valid choices, but make sure before writing.
+    Profile::parse_str(&spec, &out).map_err(|e| LoadError::Profile(name.to_string(), e))?;
+
+    std::fs::create_dir_all(&workspace.profiles_dir)
+        .and_then(|_| std::fs::write(&path, out))
+        .map_err(|e| Error::Write(path.clone(), e))?;
+    println!("\nWrote {}", path.display());
+    Ok(())
+}
+
+fn list(workspace: &Workspace, args: ListArgs) -> Result<(), Error> {
+    let enabling = match args.enabling.as_deref() {
+        Some(x) => match x.split_once('.') {
+            Some(v) => Some(v),
+            None => {
+                eprintln!("Expected `--enabling` in the form `type.field`.");
+                exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let spec_matches = |spec: &Spec| {
+        let has_type = match args.ty.as_deref() {
+            Some(ty) => spec.find_type(ty).is_some(),
+            None => true,
+        };
+        let has_field = match enabling {
+            Some((ty, field)) => spec
+                .find_type(ty)
+                .and_then(|(index, _)| spec.fields.get(index))
+                .map(|x| x.keys().any(|k| k.as_str() == field))
+                .unwrap_or(false),
+            None => true,
+        };
+        has_type && has_field
+    };
+
+    let profile_matches = |name: &str, profile: &Profile| {
+        if !Selector::any_matches(&args.filter, name, &profile.tags) {
+            return false;
+        }
+
+        let enabled = |ty: &str, field: Option<&str>| {
+            let key = match profile.spec.find_type(ty) {
+                Some((_, v)) => &v.key,
+                None => return false,
+            };
+            match (profile.config.get(key), field) {
+                (Some(fields), Some(field)) => fields.keys().any(|k| k.as_str() == field),
+                (Some(fields), None) => !fields.is_empty(),
+                (None, _) => false,
+            }
+        };
+        let has_type = match args.ty.as_deref() {
+            Some(ty) => enabled(ty, None),
+            None => true,
+        };
+        let has_field = match enabling {
+            Some((ty, field)) => enabled(ty, Some(field)),
+            None => true,
+        };
+        has_type && has_field
+    };
+
+    let mut profiles = vec![];
+    for name in workspace.profile_names()? {
+        match workspace.load_profile(&name) {
+            Ok(profile) if profile_matches(&name, &profile) => profiles.push((name, profile)),
+            Ok(_) => {}
+            Err(e) => eprintln!("warning: skipping profile: {}", format_error(&e)),
+        }
+    }
+
+    let mut specs = vec![];
+    for name in workspace.spec_names()? {
+        match workspace.load_spec(&name) {
+            Ok(spec) if spec_matches(&spec) => specs.push((name, spec)),
+            Ok(_) => {}
+            Err(e) => eprintln!("warning: skipping spec: {}", format_error(&e)),
         }
     }
 
-    Ok((specs, profiles))
+    match args.format {
+        OutputFormat::Text => {
+            println!("Profiles:");
+            for (name, profile) in profiles.iter() {
+                println!(
+                    "  {} ({}): {}",
+                    name, profile.spec_name, profile.description
+                );
+                if !profile.tags.is_empty() {
+                    println!("    tags: {}", profile.tags.join(", "));
+                }
+                if !profile.bins.is_empty() {
+                    println!("    bins: {}", profile.bins.join(", "));
+                }
+                if !profile.libs.is_empty() {
+                    println!("    libs: {}", profile.libs.join(", "));
+                }
+                println!("    enabled fields: {}", profile.enabled_field_count());
+            }
+
+            println!("\nSpecs:");
+            for (name, spec) in specs.iter() {
+                println!("  {}: {}", name, spec.name);
+                for (index, ty) in spec.types.iter() {
+                    let fields = spec.fields.get(index).map(|x| x.len()).unwrap_or(0);
+                    println!(
+                        "    {} (key: {}, {}, {} fields)",
+                        index,
+                        ty.key,
+                        if ty.is_single { "single" } else { "multi" },
+                        fields
+                    );
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let profiles = profiles
+                .iter()
+                .map(|(name, profile)| {
+                    let fields = profile
+                        .config
+                        .iter()
+                        .flat_map(|(ty, fields)| {
+                            fields.keys().map(move |f| format!("{}.{}", ty, f))
+                        })
+                        .collect::<Vec<_>>();
+                    serde_json::json!({
+                        "name": name,
+                        "description": profile.description,
+                        "tags": profile.tags,
+                        "spec": profile.spec_name,
+                        "bins": profile.bins,
+                        "libs": profile.libs,
+                        "enabled_fields": fields,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let specs = specs
+                .iter()
+                .map(|(name, spec)| {
+                    let types = spec
+                        .types
+                        .iter()
+                        .map(|(index, ty)| {
+                            let fields = spec
+                                .fields
+                                .get(index)
+                                .map(|x| x.keys().map(|k| k.to_string()).collect::<Vec<_>>())
+                                .unwrap_or_default();
+                            serde_json::json!({
+                                "index": index.to_string(),
+                                "key": ty.key.to_string(),
+                                "single": ty.is_single,
+                                "fields": fields,
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    serde_json::json!({
+                        "name": name,
+                        "title": spec.name,
+                        "types": types,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let out = serde_json::json!({ "profiles": profiles, "specs": specs });
+            println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        }
+    }
+
+    Ok(())
 }
 
 pub fn run(args: Vec<String>) -> Result<(), Error> {
@@ -117,7 +1097,7 @@ pub fn run(args: Vec<String>) -> Result<(), Error> {
         }
     };
 
-    let (specs, profiles) = load_data()?;
+    let workspace = Workspace::locate()?;
 
     let command = args.command.unwrap();
     match command {
@@ -131,30 +1111,227 @@ pub fn run(args: Vec<String>) -> Result<(), Error> {
                 exit(0);
             }
 
+            let profile = match (&spec, profile) {
+                (None, None) => Some(resolve_profile(&workspace, None)?),
+                (_, profile) => profile,
+            };
+
             if let Some(spec_name) = spec {
-                let spec = match specs.get(&spec_name) {
-                    Some(v) => v,
-                    None => {
-                        eprintln!("No spec found with the name `{}`.", &spec_name);
-                        exit(1);
-                    }
-                };
+                let spec = load_spec(&workspace, &spec_name)?;
 
                 println!("{}: {}", spec_name, spec);
             }
 
             if let Some(profile_name) = profile {
-                let profile = match profiles.get(&profile_name) {
-                    Some(v) => v,
-                    None => {
-                        eprintln!("No profile found with the name `{}`.", &profile_name);
-                        exit(1);
-                    }
-                };
+                let profile = load_profile(&workspace, &profile_name)?;
 
                 println!("{}: {}", profile_name, profile);
             }
         }
+        Command::Build(BuildArgs {
+            help,
+            profile,
+            args,
+            all,
+            filter,
+            jobs,
+            skip_unchanged,
+        }) => {
+            if help {
+                BuildArgs::print_usage();
+                exit(0);
+            }
+
+            if all || !filter.is_empty() {
+                // With no profile to name, any free arguments belong to cargo.
+                let args = profile.into_iter().chain(args).collect::<Vec<_>>();
+                return build_many(&workspace, &filter, &args, jobs, skip_unchanged);
+            }
+
+            let profile_name = resolve_profile(&workspace, profile)?;
+            let profile = load_profile(&workspace, &profile_name)?;
+
+            if skip_unchanged
+                && manifest::unchanged_artifacts(
+                    &workspace.root,
+                    &workspace.profile_dist_dir(&profile_name),
+                    &profile,
+                    &args,
+                )
+                .is_some()
+            {
+                println!("Profile `{}` is unchanged; skipping build.", profile_name);
+                return Ok(());
+            }
+
+            match build::build(&workspace, &profile_name, &profile, &args, None) {
+                Ok(paths) => {
+                    for path in paths {
+                        println!("Copied {}", path.display());
+                    }
+                    println!(
+                        "Wrote {}",
+                        workspace
+                            .profile_dist_dir(&profile_name)
+                            .join(manifest::FILE_NAME)
+                            .display()
+                    );
+                }
+                Err(build::Error::Failed(code)) => exit(code.unwrap_or(1)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Command::Validate(args) => {
+            if args.help {
+                ValidateArgs::print_usage();
+                exit(0);
+            }
+
+            validate(&workspace, args)?;
+        }
+        Command::List(args) => {
+            if args.help {
+                ListArgs::print_usage();
+                exit(0);
+            }
+
+            list(&workspace, args)?;
+        }
+        Command::Env(EnvArgs {
+            help,
+            profile,
+            shell,
+        }) => {
+            if help {
+                EnvArgs::print_usage();
+                exit(0);
+            }
+
+            let profile_name = resolve_profile(&workspace, profile)?;
+
+            let profile = load_profile(&workspace, &profile_name)?;
+            print!("{}", shell.render(&profile.env_vars()?)?);
+        }
+        Command::Activate(ActivateArgs {
+            help,
+            profile,
+            no_cargo_config,
+            no_vscode,
+        }) => {
+            if help {
+                ActivateArgs::print_usage();
+                exit(0);
+            }
+
+            let profile_name = resolve_profile(&workspace, profile)?;
+
+            let profile = load_profile(&workspace, &profile_name)?;
+            let targets = Targets {
+                cargo_config: !no_cargo_config,
+                vscode: !no_vscode,
+            };
+            activate::activate(&workspace, &profile_name, &profile, targets)?;
+            println!("Activated profile `{}`.", profile_name);
+        }
+        Command::Diff(args) => {
+            if args.help {
+                DiffArgs::print_usage();
+                exit(0);
+            }
+
+            diff(&workspace, args)?;
+        }
+        Command::Matrix(args) => {
+            if args.help {
+                MatrixArgs::print_usage();
+                exit(0);
+            }
+
+            matrix(&workspace, args)?;
+        }
+        Command::Docs(args) => {
+            if args.help {
+                DocsArgs::print_usage();
+                exit(0);
+            }
+
+            docs(&workspace, args)?;
+        }
+        Command::Graph(args) => {
+            if args.help {
+                GraphArgs::print_usage();
+                exit(0);
+            }
+
+            graph(&workspace, args)?;
+        }
+        Command::Schema(args) => {
+            if args.help {
+                SchemaArgs::print_usage();
+                exit(0);
+            }
+
+            schema(&workspace, args)?;
+        }
+        Command::New(args) => {
+            if args.help {
+                NewArgs::print_usage();
+                exit(0);
+            }
+
+            let name = match args.name.clone() {
+                Some(v) => v,
+                None => {
+                    eprintln!("No profile name given.");
+                    exit(2);
+                }
+            };
+            new_profile(&workspace, &name, args)?;
+        }
+        Command::Use(UseArgs {
+            help,
+            profile,
+            clear,
+        }) => {
+            if help {
+                UseArgs::print_usage();
+                exit(0);
+            }
+
+            if clear {
+                workspace.select_profile(None)?;
+                println!("Cleared the selected profile.");
+            } else if let Some(profile_name) = profile {
+                // Fail early rather than persisting a profile that cannot load.
+                load_profile(&workspace, &profile_name)?;
+                workspace.select_profile(Some(&profile_name))?;
+                println!("Using profile `{}`.", profile_name);
+            } else {
+                match workspace.current_profile()? {
+                    Some((name, source)) => println!("{} ({})", name, source),
+                    None => println!("No profile selected."),
+                }
+                return Ok(());
+            }
+
+            if let Some((name, ProfileSource::Env)) = workspace.current_profile()? {
+                eprintln!(
+                    "warning: PBUILD_PROFILE is set to `{}` and takes precedence.",
+                    name
+                );
+            }
+        }
+        Command::Deactivate(DeactivateArgs { help }) => {
+            if help {
+                DeactivateArgs::print_usage();
+                exit(0);
+            }
+
+            match activate::deactivate(&workspace)? {
+                Some(name) => println!("Deactivated profile `{}`.", name),
+                None => println!("No profile is active."),
+            }
+        }
     }
 
     Ok(())
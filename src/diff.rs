@@ -0,0 +1,239 @@
+use std::fmt::Display;
+
+use indexmap::IndexMap;
+use serde_json::json;
+
+use crate::{cfg, profile::Profile};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Only the second profile has the entry, with its value if it has one.
+    Added(Option<String>),
+    /// Only the first profile has the entry, with its value if it has one.
+    Removed(Option<String>),
+    /// Both profiles have the entry, with different values.
+    Changed(String, String),
+}
+
+/// One difference between two profiles, such as a feature only one of them
+/// enables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub section: &'static str,
+    pub key: String,
+    pub change: Change,
+}
+
+impl Difference {
+    pub fn to_json(&self) -> serde_json::Value {
+        match &self.change {
+            Change::Added(v) => json!({
+                "section": self.section,
+                "key": self.key,
+                "change": "added",
+                "value": v,
+            }),
+            Change::Removed(v) => json!({
+                "section": self.section,
+                "key": self.key,
+                "change": "removed",
+                "value": v,
+            }),
+            Change::Changed(from, to) => json!({
+                "section": self.section,
+                "key": self.key,
+                "change": "changed",
+                "from": from,
+                "to": to,
+            }),
+        }
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.change {
+            Change::Added(None) => write!(f, "+ {}", self.key),
+            Change::Added(Some(v)) => write!(f, "+ {} = {}", self.key, v),
+            Change::Removed(None) => write!(f, "- {}", self.key),
+            Change::Removed(Some(v)) => write!(f, "- {} = {}", self.key, v),
+            Change::Changed(from, to) => write!(f, "~ {}: {} -> {}", self.key, from, to),
+        }
+    }
+}
+
+/// Compares the resolved contents of two profiles: spec, target, rustflags,
+/// cargo args, bins, libs, features, environment variables, enabled fields,
+/// property values and the resulting cfg flags. Differences are grouped by
+/// section, in that order.
+///
+/// Only the description and tags are left out, as they do not affect the
+/// build.
+pub fn diff(a: &Profile, b: &Profile) -> Vec<Difference> {
+    let mut out = vec![];
+
+    let mut scalar = |section: &'static str, a: Option<String>, b: Option<String>| {
+        let change = match (a, b) {
+            (Some(a), Some(b)) if a != b => Change::Changed(a, b),
+            (None, Some(b)) => Change::Added(Some(b)),
+            (Some(a), None) => Change::Removed(Some(a)),
+            _ => return,
+        };
+        out.push(Difference {
+            section,
+            key: section.to_string(),
+            change,
+        });
+    };
+    // Flags are compared as a whole, as their order matters.
+    let flags = |x: &[String]| Some(cfg::to_shell(x)).filter(|x| !x.is_empty());
+
+    scalar("spec", Some(a.spec_name.clone()), Some(b.spec_name.clone()));
+    scalar("target", a.target.clone(), b.target.clone());
+    scalar("rustflags", flags(&a.rustflags), flags(&b.rustflags));
+    scalar("cargo_args", flags(&a.cargo_args), flags(&b.cargo_args));
+
+    out.extend(diff_lists("bins", &a.bins, &b.bins));
+    out.extend(diff_lists("libs", &a.libs, &b.libs));
+    out.extend(diff_lists("features", &a.features, &b.features));
+    out.extend(diff_maps("env", &a.env, &b.env));
+    out.extend(diff_lists("fields", &enabled_fields(a), &enabled_fields(b)));
+    out.extend(diff_maps("properties", &properties(a), &properties(b)));
+
+    let cfg = |p: &Profile| {
+        p.cfg_flags()
+            .iter()
+            .map(|x| x.to_rustc_arg())
+            .collect::<Vec<_>>()
+    };
+    out.extend(diff_lists("cfg", &cfg(a), &cfg(b)));
+
+    out
+}
+
+/// Entries removed from `a` followed by entries added in `b`, ignoring order.
+fn diff_lists(section: &'static str, a: &[String], b: &[String]) -> Vec<Difference> {
+    let removed = a.iter().filter(|x| !b.contains(x)).map(|x| Difference {
+        section,
+        key: x.to_string(),
+        change: Change::Removed(None),
+    });
+    let added = b.iter().filter(|x| !a.contains(x)).map(|x| Difference {
+        section,
+        key: x.to_string(),
+        change: Change::Added(None),
+    });
+    removed.chain(added).collect()
+}
+
+/// Entries removed from or changed between `a` and `b`, in the order of `a`,
+/// followed by entries added in `b`.
+fn diff_maps(
+    section: &'static str,
+    a: &IndexMap<String, String>,
+    b: &IndexMap<String, String>,
+) -> Vec<Difference> {
+    let mut out = vec![];
+    for (key, a_value) in a.iter() {
+        let change = match b.get(key) {
+            Some(b_value) if a_value != b_value => {
+                Change::Changed(a_value.to_string(), b_value.to_string())
+            }
+            Some(_) => continue,
+            None => Change::Removed(Some(a_value.to_string())),
+        };
+        out.push(Difference {
+            section,
+            key: key.to_string(),
+            change,
+        });
+    }
+    for (key, b_value) in b.iter() {
+        if !a.contains_key(key) {
+            out.push(Difference {
+                section,
+                key: key.to_string(),
+                change: Change::Added(Some(b_value.to_string())),
+            });
+        }
+    }
+    out
+}
+
+fn enabled_fields(profile: &Profile) -> Vec<String> {
+    profile
+        .config
+        .iter()
+        .flat_map(|(ty, fields)| fields.keys().map(move |f| format!("{}.{}", ty, f)))
+        .collect()
+}
+
+fn properties(profile: &Profile) -> IndexMap<String, String> {
+    profile
+        .config
+        .iter()
+        .flat_map(|(ty, fields)| {
+            fields.iter().flat_map(move |(field, props)| {
+                props
+                    .iter()
+                    .map(move |(k, v)| (format!("{}.{}.{}", ty, field, k), v.to_string()))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::Spec;
+
+    fn profile(spec: &Spec, extra: &str) -> Profile {
+        Profile::parse_str(
+            spec,
+            &format!(
+                "[profile]\ndescription = \"Test\"\nbins = [\"app\"]\n{}\n[config]\nboard = \"dk\"\n",
+                extra
+            ),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn compares_flags_args_and_env() {
+        let spec = Spec::parse_str(
+            "[spec]\nname = \"Test\"\n[spec.types]\nboard = { key = \"board\", single = true }\n\
+             [board.dk]\ndescription = \"DK\"\n",
+        )
+        .unwrap();
+        let a = profile(
+            &spec,
+            "rustflags = [\"-C\", \"debuginfo=1\"]\ncargo_args = [\"--locked\"]\n\
+             [env]\nA = \"1\"\nB = \"2\"\n",
+        );
+        let b = profile(
+            &spec,
+            "rustflags = [\"-C\", \"debuginfo=2\"]\n[env]\nA = \"3\"\nC = \"4\"\n",
+        );
+
+        let differences = diff(&a, &b)
+            .into_iter()
+            .map(|x| (x.section, x.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            differences,
+            [
+                (
+                    "rustflags",
+                    "~ rustflags: -C debuginfo=1 -> -C debuginfo=2".to_string()
+                ),
+                ("cargo_args", "- cargo_args = --locked".to_string()),
+                ("env", "~ A: 1 -> 3".to_string()),
+                ("env", "- B = 2".to_string()),
+                ("env", "+ C = 4".to_string()),
+            ]
+        );
+
+        let c = profile(&spec, "tags = [\"other\"]");
+        assert_eq!(diff(&profile(&spec, ""), &c), []);
+    }
+}
@@ -0,0 +1,403 @@
+use std::str::FromStr;
+
+use crate::{
+    matrix::html_escape,
+    profile::Profile,
+    spec::{DependencyOp, PropSpec, Spec, TypeSpec},
+};
+
+/// Formats documentation can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Html,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            _ => Err(format!(
+                "unknown format `{}`, expected `markdown` or `html`",
+                s
+            )),
+        }
+    }
+}
+
+enum Inline {
+    Text(String),
+    Code(String),
+}
+
+fn text(s: impl Into<String>) -> Inline {
+    Inline::Text(s.into())
+}
+
+fn code(s: impl Into<String>) -> Inline {
+    Inline::Code(s.into())
+}
+
+enum Block {
+    Heading(usize, Vec<Inline>),
+    Paragraph(Vec<Inline>),
+    Table(Vec<&'static str>, Vec<Vec<Vec<Inline>>>),
+    Code(String),
+}
+
+/// A spec to document, by name, with the profiles using it.
+pub type SpecDocs<'a> = (&'a str, &'a Spec, Vec<(&'a str, &'a Profile)>);
+
+/// Renders the documentation of one or more specs as a single document, one
+/// section per spec: its types, every field with its description,
+/// dependencies, properties and cfg flags, and which of the given profiles
+/// enable it.
+pub fn render(specs: &[SpecDocs], format: Format) -> String {
+    let sections = specs
+        .iter()
+        .map(|(name, spec, profiles)| (*name, document(name, spec, profiles)))
+        .collect::<Vec<_>>();
+    match format {
+        Format::Markdown => sections
+            .iter()
+            .map(|(_, blocks)| to_markdown(blocks))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Format::Html => {
+            let title = match specs {
+                [(_, spec, _)] => spec.name.as_str(),
+                _ => "Specs",
+            };
+            to_html(title, &sections)
+        }
+    }
+}
+
+fn document(name: &str, spec: &Spec, profiles: &[(&str, &Profile)]) -> Vec<Block> {
+    let mut out = vec![
+        Block::Heading(1, vec![text(&spec.name)]),
+        Block::Paragraph(vec![
+            text("Spec "),
+            code(name),
+            text(format!(", used by {}.", count(profiles.len(), "profile"))),
+        ]),
+    ];
+
+    out.push(Block::Table(
+        vec!["Type", "Kind", "Fields", "cfg"],
+        spec.types
+            .iter()
+            .map(|(index, ty)| {
+                let fields = spec.fields.get(index).map(|x| x.len()).unwrap_or(0);
+                vec![
+                    vec![code(ty.key.to_string())],
+                    vec![text(kind(ty))],
+                    vec![text(fields.to_string())],
+                    vec![code(placeholder_cfg(ty))],
+                ]
+            })
+            .collect(),
+    ));
+
+    for (index, fields) in spec.fields.iter() {
+        let ty = &spec.types[index];
+        out.push(Block::Heading(
+            2,
+            vec![text("Type "), code(ty.key.to_string())],
+        ));
+        out.push(Block::Paragraph(vec![text(if ty.is_single {
+            "Single: a profile enables exactly one of these fields."
+        } else {
+            "Multi: a profile may enable any number of these fields."
+        })]));
+
+        for (field, field_spec) in fields.iter() {
+            out.push(Block::Heading(
+                3,
+                vec![code(format!("{}.{}", ty.key, field))],
+            ));
+            if !field_spec.description.is_empty() {
+                out.push(Block::Paragraph(vec![text(&field_spec.description)]));
+            }
+
+            let deps = &*field_spec.dependencies;
+            if !deps.deps().is_empty() {
+                let mut requires = vec![text("Requires: ")];
+                dependency_inlines(deps, &mut requires);
+                requires.push(text("."));
+                out.push(Block::Paragraph(requires));
+            }
+
+            out.push(Block::Paragraph(vec![
+                text("Sets "),
+                code(field_cfg(ty, field)),
+                text(" when enabled."),
+            ]));
+
+            if !field_spec.properties.is_empty() {
+                out.push(Block::Table(
+                    vec!["Property", "Type", "Default", "Constraints", "cfg"],
+                    field_spec
+                        .properties
+                        .iter()
+                        .map(|(prop, prop_spec)| {
+                            vec![
+                                vec![code(prop)],
+                                vec![text(prop_spec.ty.as_str())],
+                                match prop_spec.default.as_ref() {
+                                    Some(v) => vec![code(v.to_string())],
+                                    None => vec![],
+                                },
+                                constraints(prop_spec),
+                                vec![code(ty.property_cfg_name(field, prop))],
+                            ]
+                        })
+                        .collect(),
+                ));
+            }
+
+            let enable = if ty.is_single {
+                format!("[config]\n{} = \"{}\"", ty.key, field)
+            } else {
+                format!("[{}]\n{} = true", index, field)
+            };
+            out.push(Block::Code(enable));
+
+            let users = profiles
+                .iter()
+                .filter(|(_, p)| p.is_enabled(&ty.key, field))
+                .map(|(n, _)| *n)
+                .collect::<Vec<_>>();
+            let mut used_by = vec![text("Used by: ")];
+            if users.is_empty() {
+                used_by.push(text("no profiles"));
+            } else {
+                for (i, user) in users.iter().enumerate() {
+                    if i > 0 {
+                        used_by.push(text(", "));
+                    }
+                    used_by.push(code(*user));
+                }
+            }
+            used_by.push(text("."));
+            out.push(Block::Paragraph(used_by));
+        }
+    }
+
+    out
+}
+
+fn count(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", n, noun)
+    }
+}
+
+fn kind(ty: &TypeSpec) -> &'static str {
+    if ty.is_single {
+        "single"
+    } else {
+        "multi"
+    }
+}
+
+fn field_cfg(ty: &TypeSpec, field: &str) -> String {
+    if ty.is_key_value() {
+        format!("{}=\"{}\"", ty.cfg_name(), field)
+    } else {
+        ty.field_cfg_name(field)
+    }
+}
+
+/// The cfg a type's fields set, with `<field>` standing in for the field's
+/// name as it is written, before any case conversion.
+fn placeholder_cfg(ty: &TypeSpec) -> String {
+    if ty.is_key_value() {
+        format!("{}=\"<field>\"", ty.cfg_name())
+    } else {
+        format!("{}_<field>", ty.cfg_name())
+    }
+}
+
+/// Renders dependencies like `a and (b or c)`, with each field as code.
+fn dependency_inlines(op: &DependencyOp, out: &mut Vec<Inline>) {
+    match op {
+        DependencyOp::Or(deps) => {
+            if deps.len() > 1 {
+                out.push(text("("));
+            }
+            for (i, dep) in deps.iter().enumerate() {
+                if i > 0 {
+                    out.push(text(" or "));
+                }
+                out.push(code(format!("{}.{}", dep.ty, dep.name)));
+            }
+            if deps.len() > 1 {
+                out.push(text(")"));
+            }
+        }
+        DependencyOp::And(ops) => {
+            for (i, op) in ops.iter().enumerate() {
+                if i > 0 {
+                    out.push(text(" and "));
+                }
+                dependency_inlines(op, out);
+            }
+        }
+        DependencyOp::Dep(dep) => out.push(code(format!("{}.{}", dep.ty, dep.name))),
+    }
+}
+
+fn constraints(prop: &PropSpec) -> Vec<Inline> {
+    let mut out = vec![];
+    let mut push = |label: &str, value: String| {
+        if !out.is_empty() {
+            out.push(text(", "));
+        }
+        out.push(text(label));
+        out.push(code(value));
+    };
+    if let Some(min) = prop.min.as_ref() {
+        push("min ", min.to_string());
+    }
+    if let Some(max) = prop.max.as_ref() {
+        push("max ", max.to_string());
+    }
+    if !prop.values.is_empty() {
+        let values = prop
+            .values
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        push("one of ", values.join(", "));
+    }
+    out
+}
+
+fn markdown_inlines(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|x| match x {
+            Inline::Text(s) => s.replace('|', "\\|"),
+            Inline::Code(s) => format!("`{}`", s.replace('|', "\\|")),
+        })
+        .collect()
+}
+
+fn to_markdown(blocks: &[Block]) -> String {
+    let mut out = vec![];
+    for block in blocks.iter() {
+        out.push(match block {
+            Block::Heading(level, x) => format!("{} {}", "#".repeat(*level), markdown_inlines(x)),
+            Block::Paragraph(x) => markdown_inlines(x),
+            Block::Table(headers, rows) => {
+                let mut table = format!("| {} |\n", headers.join(" | "));
+                table.push_str(&format!("|{}", "---|".repeat(headers.len())));
+                for row in rows.iter() {
+                    let cells = row.iter().map(|x| markdown_inlines(x)).collect::<Vec<_>>();
+                    table.push_str(&format!("\n| {} |", cells.join(" | ")));
+                }
+                table
+            }
+            Block::Code(x) => format!("```toml\n{}\n```", x),
+        });
+    }
+    let mut out = out.join("\n\n");
+    out.push('\n');
+    out
+}
+
+fn html_inlines(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|x| match x {
+            Inline::Text(s) => html_escape(s),
+            Inline::Code(s) => format!("<code>{}</code>", html_escape(s)),
+        })
+        .collect()
+}
+
+fn to_html(title: &str, sections: &[(&str, Vec<Block>)]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n",
+        html_escape(title)
+    );
+    for (name, blocks) in sections.iter() {
+        out.push_str(&format!("<section id=\"{}\">\n", html_escape(name)));
+        html_blocks(blocks, &mut out);
+        out.push_str("</section>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn html_blocks(blocks: &[Block], out: &mut String) {
+    for block in blocks.iter() {
+        match block {
+            Block::Heading(level, x) => {
+                out.push_str(&format!("<h{0}>{1}</h{0}>\n", level, html_inlines(x)))
+            }
+            Block::Paragraph(x) => out.push_str(&format!("<p>{}</p>\n", html_inlines(x))),
+            Block::Table(headers, rows) => {
+                out.push_str("<table>\n  <thead>\n    <tr>");
+                for header in headers.iter() {
+                    out.push_str(&format!("<th>{}</th>", header));
+                }
+                out.push_str("</tr>\n  </thead>\n  <tbody>\n");
+                for row in rows.iter() {
+                    out.push_str("    <tr>");
+                    for cell in row.iter() {
+                        out.push_str(&format!("<td>{}</td>", html_inlines(cell)));
+                    }
+                    out.push_str("</tr>\n");
+                }
+                out.push_str("  </tbody>\n</table>\n");
+            }
+            Block::Code(x) => out.push_str(&format!(
+                "<pre><code class=\"language-toml\">{}</code></pre>\n",
+                html_escape(x)
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_html_document_for_several_specs() {
+        let spec = |name: &str| {
+            Spec::parse_str(&format!(
+                "[spec]\nname = \"{}\"\ncfg_prefix = \"pb_\"\n\
+                 [spec.types]\nradio = {{ key = \"radio\" }}\n\
+                 [radio.lora]\ndescription = \"LoRa\"\n",
+                name
+            ))
+            .unwrap()
+        };
+        let (a, b) = (spec("A"), spec("B"));
+
+        let out = render(&[("a", &a, vec![]), ("b", &b, vec![])], Format::Html);
+        assert_eq!(out.matches("<html>").count(), 1);
+        assert_eq!(out.matches("<section id=").count(), 2);
+        assert!(out.contains("<code>pb_radio_&lt;field&gt;</code>"));
+
+        let out = render(&[("a", &a, vec![])], Format::Markdown);
+        assert!(out.contains("| `radio` | multi | 1 | `pb_radio_<field>` |"));
+    }
+}
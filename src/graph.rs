@@ -0,0 +1,421 @@
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+
+use crate::{
+    profile::Profile,
+    spec::{Dep, DependencyOp, Spec, TypeKey},
+};
+
+/// Formats a dependency graph can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Dot,
+    Mermaid,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" | "graphviz" => Ok(Format::Dot),
+            "mermaid" => Ok(Format::Mermaid),
+            _ => Err(format!(
+                "unknown format `{}`, expected `dot` or `mermaid`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    Field,
+    /// Joins the alternatives of an `OR` dependency.
+    Any,
+    /// A dependency on a field the spec does not define.
+    Undefined,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    id: String,
+    label: String,
+    kind: NodeKind,
+    enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EdgeKind {
+    /// A field requires another field, or one of a set of alternatives.
+    Requires,
+    /// One of the alternatives of an `OR` dependency.
+    Alternative,
+}
+
+#[derive(Debug, Clone)]
+struct Edge {
+    from: String,
+    to: String,
+    kind: EdgeKind,
+    /// Whether the profile being highlighted breaks this edge.
+    unsatisfied: bool,
+}
+
+/// The fields of a spec as nodes, grouped by type, with their dependencies
+/// as edges. The fields of a single type, of which at most one can be
+/// enabled, form one cluster like those of any other type.
+#[derive(Debug, Clone)]
+pub struct Graph {
+    name: String,
+    /// Each type's key, single-ness and field nodes.
+    types: Vec<(TypeKey, bool, Vec<Node>)>,
+    /// Nodes outside any type: `OR` joins and undefined dependencies.
+    extra: Vec<Node>,
+    edges: Vec<Edge>,
+    /// The id of each field's node. Ids are numbered rather than derived
+    /// from names, which could collide or be invalid in either format.
+    ids: IndexMap<(TypeKey, String), String>,
+}
+
+impl Graph {
+    /// Builds the graph of a spec. If a profile is given, the fields it
+    /// enables are highlighted, as are the edges it leaves unsatisfied: a
+    /// dependency of an enabled field that is not enabled.
+    pub fn new(name: &str, spec: &Spec, profile: Option<&Profile>) -> Graph {
+        let is_enabled =
+            |ty: &TypeKey, field: &str| profile.is_some_and(|p| p.is_enabled(ty, field));
+        let is_dep_enabled = |dep: &Dep| is_enabled(&dep.ty, &dep.name);
+
+        let mut graph = Graph {
+            name: name.to_string(),
+            types: vec![],
+            extra: vec![],
+            edges: vec![],
+            ids: IndexMap::new(),
+        };
+
+        for (index, fields) in spec.fields.iter() {
+            let ty = &spec.types[index];
+            let mut nodes = vec![];
+            for field in fields.keys() {
+                nodes.push(Node {
+                    id: graph.node_id(&ty.key, field),
+                    label: field.to_string(),
+                    kind: NodeKind::Field,
+                    enabled: is_enabled(&ty.key, field),
+                });
+            }
+            graph.types.push((ty.key.clone(), ty.is_single, nodes));
+        }
+
+        for (index, fields) in spec.fields.iter() {
+            let ty = &spec.types[index].key;
+            for (field, field_spec) in fields.iter() {
+                let from = graph.node_id(ty, field);
+                let enabled = is_enabled(ty, field);
+                graph.add_dependencies(
+                    spec,
+                    &from,
+                    enabled,
+                    &field_spec.dependencies,
+                    &is_dep_enabled,
+                );
+            }
+        }
+
+        graph
+    }
+
+    /// The id of a field's node, assigning one on first use.
+    fn node_id(&mut self, ty: &TypeKey, field: &str) -> String {
+        let next = format!("f{}", self.ids.len());
+        self.ids
+            .entry((ty.clone(), field.to_string()))
+            .or_insert(next)
+            .clone()
+    }
+
+    fn add_dependencies(
+        &mut self,
+        spec: &Spec,
+        from: &str,
+        enabled: bool,
+        op: &DependencyOp,
+        is_enabled: &dyn Fn(&Dep) -> bool,
+    ) {
+        match op {
+            DependencyOp::And(ops) => {
+                for op in ops.iter() {
+                    self.add_dependencies(spec, from, enabled, op, is_enabled);
+                }
+            }
+            DependencyOp::Dep(dep) => {
+                self.add_requires(spec, from, dep, enabled && !is_enabled(dep));
+            }
+            DependencyOp::Or(deps) if deps.len() == 1 => {
+                self.add_requires(spec, from, &deps[0], enabled && !is_enabled(&deps[0]));
+            }
+            DependencyOp::Or(deps) => {
+                let any = format!("any_{}", self.extra.len());
+                let satisfied = deps.iter().any(is_enabled);
+                self.extra.push(Node {
+                    id: any.clone(),
+                    label: "or".into(),
+                    kind: NodeKind::Any,
+                    enabled: enabled && satisfied,
+                });
+                self.edges.push(Edge {
+                    from: from.to_string(),
+                    to: any.clone(),
+                    kind: EdgeKind::Requires,
+                    unsatisfied: enabled && !satisfied,
+                });
+                for dep in deps.iter() {
+                    let to = self.dep_node(spec, dep);
+                    self.edges.push(Edge {
+                        from: any.clone(),
+                        to,
+                        kind: EdgeKind::Alternative,
+                        unsatisfied: false,
+                    });
+                }
+            }
+        }
+    }
+
+    fn add_requires(&mut self, spec: &Spec, from: &str, dep: &Dep, unsatisfied: bool) {
+        let to = self.dep_node(spec, dep);
+        self.edges.push(Edge {
+            from: from.to_string(),
+            to,
+            kind: EdgeKind::Requires,
+            unsatisfied,
+        });
+    }
+
+    /// The node for a dependency, adding one for it if the spec does not
+    /// define the field.
+    fn dep_node(&mut self, spec: &Spec, dep: &Dep) -> String {
+        let id = self.node_id(&dep.ty, &dep.name);
+        if spec.field(&dep.ty, &dep.name).is_none() && !self.extra.iter().any(|x| x.id == id) {
+            self.extra.push(Node {
+                id: id.clone(),
+                label: format!("{}.{} (undefined)", dep.ty, dep.name),
+                kind: NodeKind::Undefined,
+                enabled: false,
+            });
+        }
+        id
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Dot => self.to_dot(),
+            Format::Mermaid => self.to_mermaid(),
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let quote = |x: &str| format!("\"{}\"", x.replace('\\', "\\\\").replace('"', "\\\""));
+        let node = |node: &Node| {
+            let mut attrs = vec![format!("label={}", quote(&node.label))];
+            match node.kind {
+                NodeKind::Field => {}
+                NodeKind::Any => attrs.push("shape=diamond, fontsize=10".into()),
+                NodeKind::Undefined => attrs.push("style=dashed, color=gray".into()),
+            }
+            if node.enabled {
+                attrs.push("style=filled, fillcolor=palegreen".into());
+            }
+            format!("{} [{}];", node.id, attrs.join(", "))
+        };
+
+        let mut out = format!(
+            "digraph {} {{\n  rankdir=LR;\n  node [shape=box];\n",
+            quote(&self.name)
+        );
+        for (ty, is_single, nodes) in self.types.iter() {
+            let kind = if *is_single { "single" } else { "multi" };
+            out.push_str(&format!(
+                "  subgraph {} {{\n    label={};\n",
+                quote(&format!("cluster_{}", ty)),
+                quote(&format!("{} ({})", ty, kind))
+            ));
+            for x in nodes.iter() {
+                out.push_str(&format!("    {}\n", node(x)));
+            }
+            out.push_str("  }\n");
+        }
+        for x in self.extra.iter() {
+            out.push_str(&format!("  {}\n", node(x)));
+        }
+
+        for edge in self.edges.iter() {
+            let mut attrs = match edge.kind {
+                EdgeKind::Requires => vec![],
+                EdgeKind::Alternative => vec!["style=dashed"],
+            };
+            if edge.unsatisfied {
+                attrs.extend(["color=red", "penwidth=2"]);
+            }
+            if attrs.is_empty() {
+                out.push_str(&format!("  {} -> {};\n", edge.from, edge.to));
+            } else {
+                out.push_str(&format!(
+                    "  {} -> {} [{}];\n",
+                    edge.from,
+                    edge.to,
+                    attrs.join(", ")
+                ));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn to_mermaid(&self) -> String {
+        let quote = |x: &str| format!("\"{}\"", x.replace('"', "#quot;"));
+        let node = |node: &Node| match node.kind {
+            NodeKind::Field => format!("{}[{}]", node.id, quote(&node.label)),
+            NodeKind::Any => format!("{}{{{}}}", node.id, quote(&node.label)),
+            NodeKind::Undefined => format!("{}[{}]:::undefined", node.id, quote(&node.label)),
+        };
+
+        let mut out = String::from("flowchart LR\n");
+        for (i, (ty, is_single, nodes)) in self.types.iter().enumerate() {
+            let kind = if *is_single { "single" } else { "multi" };
+            out.push_str(&format!(
+                "  subgraph t{}[{}]\n",
+                i,
+                quote(&format!("{} ({})", ty, kind))
+            ));
+            for x in nodes.iter() {
+                out.push_str(&format!("    {}\n", node(x)));
+            }
+            out.push_str("  end\n");
+        }
+        for x in self.extra.iter() {
+            out.push_str(&format!("  {}\n", node(x)));
+        }
+
+        let mut unsatisfied = vec![];
+        for (i, edge) in self.edges.iter().enumerate() {
+            let arrow = match edge.kind {
+                EdgeKind::Requires => "-->",
+                EdgeKind::Alternative => "-.->",
+            };
+            out.push_str(&format!("  {} {} {}\n", edge.from, arrow, edge.to));
+            if edge.unsatisfied {
+                unsatisfied.push(i.to_string());
+            }
+        }
+
+        out.push_str("  classDef undefined stroke-dasharray: 5 5,color:gray\n");
+        let enabled = self
+            .types
+            .iter()
+            .flat_map(|(_, _, nodes)| nodes.iter())
+            .chain(self.extra.iter())
+            .filter(|x| x.enabled)
+            .map(|x| x.id.as_str())
+            .collect::<Vec<_>>();
+        if !enabled.is_empty() {
+            out.push_str("  classDef enabled fill:#b7f0b1,stroke:#2e7d32\n");
+            out.push_str(&format!("  class {} enabled\n", enabled.join(",")));
+        }
+        if !unsatisfied.is_empty() {
+            out.push_str(&format!(
+                "  linkStyle {} stroke:red,stroke-width:3px\n",
+                unsatisfied.join(",")
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> Spec {
+        Spec::parse_str(
+            "[spec]\nname = \"main\"\n\
+             [spec.types]\nboard = { key = \"board\", single = true }\nradio = { key = \"radio\" }\n\
+             [board.nrf52-dk]\ndescription = \"DK\"\n\
+             [board.nrf52_dk]\ndescription = \"Other DK\"\n\
+             [board.9x]\ndescription = \"9x\"\n\
+             [radio.lora]\ndescription = \"LoRa\"\ndependencies = [\"board:nrf52-dk\"]\n\
+             [radio.ble]\ndescription = \"BLE\"\ndependencies = [\"board:nrf52_dk OR board:9x\"]\n",
+        )
+        .unwrap()
+    }
+
+    fn profile(spec: &Spec) -> Profile {
+        Profile::parse_str(
+            spec,
+            "[profile]\ndescription = \"P\"\nbins = [\"app\"]\n\
+             [config]\nboard = \"9x\"\n[radio]\nlora = true\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_dot() {
+        let spec = spec();
+        let graph = Graph::new("main", &spec, Some(&profile(&spec)));
+        assert_eq!(
+            graph.render(Format::Dot),
+            "digraph \"main\" {\n  rankdir=LR;\n  node [shape=box];\n\
+             \x20 subgraph \"cluster_board\" {\n    label=\"board (single)\";\n\
+             \x20   f0 [label=\"nrf52-dk\"];\n\
+             \x20   f1 [label=\"nrf52_dk\"];\n\
+             \x20   f2 [label=\"9x\", style=filled, fillcolor=palegreen];\n  }\n\
+             \x20 subgraph \"cluster_radio\" {\n    label=\"radio (multi)\";\n\
+             \x20   f3 [label=\"lora\", style=filled, fillcolor=palegreen];\n\
+             \x20   f4 [label=\"ble\"];\n  }\n\
+             \x20 any_0 [label=\"or\", shape=diamond, fontsize=10];\n\
+             \x20 f3 -> f0 [color=red, penwidth=2];\n\
+             \x20 f4 -> any_0;\n\
+             \x20 any_0 -> f1 [style=dashed];\n\
+             \x20 any_0 -> f2 [style=dashed];\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn renders_mermaid() {
+        let spec = spec();
+        let graph = Graph::new("main", &spec, Some(&profile(&spec)));
+        assert_eq!(
+            graph.render(Format::Mermaid),
+            "flowchart LR\n\
+             \x20 subgraph t0[\"board (single)\"]\n\
+             \x20   f0[\"nrf52-dk\"]\n    f1[\"nrf52_dk\"]\n    f2[\"9x\"]\n  end\n\
+             \x20 subgraph t1[\"radio (multi)\"]\n\
+             \x20   f3[\"lora\"]\n    f4[\"ble\"]\n  end\n\
+             \x20 any_0{\"or\"}\n\
+             \x20 f3 --> f0\n  f4 --> any_0\n  any_0 -.-> f1\n  any_0 -.-> f2\n\
+             \x20 classDef undefined stroke-dasharray: 5 5,color:gray\n\
+             \x20 classDef enabled fill:#b7f0b1,stroke:#2e7d32\n\
+             \x20 class f2,f3 enabled\n\
+             \x20 linkStyle 0 stroke:red,stroke-width:3px\n"
+        );
+    }
+
+    #[test]
+    fn undefined_dependencies_get_their_own_node() {
+        let spec = Spec::parse_str(
+            "[spec]\nname = \"main\"\n\
+             [spec.types]\nboard = { key = \"board\" }\nradio = { key = \"radio\" }\n\
+             [board.other]\ndescription = \"Other\"\n\
+             [radio.lora]\ndescription = \"LoRa\"\ndependencies = [\"board:dk\"]\n\
+             [radio.ble]\ndescription = \"BLE\"\ndependencies = [\"board:dk\"]\n",
+        )
+        .unwrap();
+        let dot = Graph::new("main", &spec, None).render(Format::Dot);
+        assert_eq!(dot.matches("(undefined)").count(), 1, "{}", dot);
+        assert!(dot.contains("  f1 -> f3;\n  f2 -> f3;\n"), "{}", dot);
+    }
+}
@@ -1,3 +1,18 @@
+pub mod activate;
+pub mod build;
+pub mod cfg;
 pub mod cli;
+pub mod diff;
+pub mod docs;
+pub mod graph;
+pub mod lint;
+pub mod manifest;
+pub mod matrix;
 pub mod profile;
+pub mod schema;
+pub mod select;
+pub mod shell;
 pub mod spec;
+pub mod state;
+pub mod wizard;
+pub mod workspace;
@@ -0,0 +1,475 @@
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use indexmap::IndexMap;
+
+use crate::{
+    profile::Profile,
+    spec::{Dep, Spec, TypeKey},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Level::Allow),
+            "warn" => Ok(Level::Warn),
+            "deny" => Ok(Level::Deny),
+            _ => Err(format!(
+                "unknown lint level `{}`, expected `allow`, `warn` or `deny`",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Level::Allow => "allow",
+            Level::Warn => "warn",
+            Level::Deny => "deny",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnsatisfiedDependency,
+    PropertyConstraint,
+    UnusedField,
+    UnreachableField,
+    DuplicateProfile,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UnsatisfiedDependency,
+        Lint::PropertyConstraint,
+        Lint::UnusedField,
+        Lint::UnreachableField,
+        Lint::DuplicateProfile,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnsatisfiedDependency => "unsatisfied-dependency",
+            Lint::PropertyConstraint => "property-constraint",
+            Lint::UnusedField => "unused-field",
+            Lint::UnreachableField => "unreachable-field",
+            Lint::DuplicateProfile => "duplicate-profile",
+        }
+    }
+
+    pub fn default_level(&self) -> Level {
+        match self {
+            Lint::UnsatisfiedDependency => Level::Deny,
+            Lint::PropertyConstraint => Level::Deny,
+            Lint::UnusedField => Level::Warn,
+            Lint::UnreachableField => Level::Warn,
+            Lint::DuplicateProfile => Level::Warn,
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL
+            .iter()
+            .find(|x| x.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown lint `{}`", s))
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Lint levels, falling back to each lint's default level.
+#[derive(Debug, Clone, Default)]
+pub struct Levels(IndexMap<Lint, Level>);
+
+impl Levels {
+    pub fn get(&self, lint: Lint) -> Level {
+        self.0
+            .get(&lint)
+            .copied()
+            .unwrap_or_else(|| lint.default_level())
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.0.insert(lint, level);
+    }
+
+    /// Raises every lint that would warn to an error.
+    pub fn deny_warnings(&mut self) {
+        for lint in Lint::ALL {
+            if self.get(lint) == Level::Warn {
+                self.set(lint, Level::Deny);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Levels) {
+        for (lint, level) in other.0.iter() {
+            self.set(*lint, *level);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub origin: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(lint: Lint, origin: &str, message: String) -> Diagnostic {
+        Diagnostic {
+            lint,
+            origin: origin.to_string(),
+            message,
+        }
+    }
+}
+
+/// Checks a spec for fields that can never be enabled.
+pub fn check_spec(name: &str, spec: &Spec) -> Vec<Diagnostic> {
+    let origin = format!("spec `{}`", name);
+    unreachable_fields(spec)
+        .into_iter()
+        .map(|message| Diagnostic::new(Lint::UnreachableField, &origin, message))
+        .collect()
+}
+
+/// Checks a profile's enabled fields against their dependencies and its
+/// property values against their constraints.
+pub fn check_profile(name: &str, profile: &Profile) -> Vec<Diagnostic> {
+    let origin = format!("profile `{}`", name);
+    let mut out = vec![];
+
+    for (ty, field, deps) in profile.unsatisfied_dependencies() {
+        out.push(Diagnostic::new(
+            Lint::UnsatisfiedDependency,
+            &origin,
+            format!("`{}.{}` requires {}", ty, field, deps),
+        ));
+    }
+
+    for (prop, reason) in profile.constraint_violations() {
+        out.push(Diagnostic::new(
+            Lint::PropertyConstraint,
+            &origin,
+            format!("`{}`: {}", prop, reason),
+        ));
+    }
+
+    out
+}
+
+/// Reports fields of a spec that none of the given profiles enable.
+pub fn check_unused_fields(name: &str, spec: &Spec, profiles: &[&Profile]) -> Vec<Diagnostic> {
+    let origin = format!("spec `{}`", name);
+    let mut out = vec![];
+
+    for (index, fields) in spec.fields.iter() {
+        let ty = &spec.types[index];
+        for field in fields.keys() {
+            if !profiles.iter().any(|p| p.is_enabled(&ty.key, field)) {
+                out.push(Diagnostic::new(
+                    Lint::UnusedField,
+                    &origin,
+                    format!("`{}.{}` is not enabled by any profile", ty.key, field),
+                ));
+            }
+        }
+    }
+
+    out
+}
+
+/// Reports profiles with the same fingerprint as an earlier profile, as they
+/// build exactly the same thing.
+pub fn check_duplicate_profiles(profiles: &[(&str, &Profile)]) -> Vec<Diagnostic> {
+    let mut seen: IndexMap<String, &str> = IndexMap::new();
+    let mut out = vec![];
+
+    for (name, profile) in profiles.iter() {
+        let fingerprint = profile.fingerprint();
+        match seen.get(&fingerprint) {
+            Some(first) => out.push(Diagnostic::new(
+                Lint::DuplicateProfile,
+                &format!("profile `{}`", name),
+                format!("resolves to the same configuration as profile `{}`", first),
+            )),
+            None => {
+                seen.insert(fingerprint, name);
+            }
+        }
+    }
+
+    out
+}
+
+/// Finds fields whose dependencies can never be satisfied, either because
+/// they depend on fields that do not exist (or are themselves unreachable),
+/// or because they require two different fields of a single type.
+fn unreachable_fields(spec: &Spec) -> Vec<String> {
+    let mut reachable = spec
+        .fields
+        .iter()
+        .flat_map(|(index, fields)| {
+            let key = spec.types[index].key.clone();
+            fields.keys().map(move |f| (key.clone(), f.to_string()))
+        })
+        .collect::<HashSet<_>>();
+
+    let mut out = vec![];
+
+    loop {
+        let mut removed = vec![];
+
+        for (index, fields) in spec.fields.iter() {
+            let ty = &spec.types[index];
+            for (field, field_spec) in fields.iter() {
+                let id = (ty.key.clone(), field.to_string());
+                if !reachable.contains(&id) {
+                    continue;
+                }
+
+                let deps = &*field_spec.dependencies;
+                let is_reachable =
+                    |dep: &Dep| reachable.contains(&(dep.ty.clone(), dep.name.clone()));
+
+                let reason = if !deps.is_satisfied(is_reachable) {
+                    let missing = deps
+                        .deps()
+                        .into_iter()
+                        .filter(|x| !is_reachable(x))
+                        .map(|x| {
+                            if spec.field(&x.ty, &x.name).is_some() {
+                                format!("`{}` can never be enabled", x)
+                            } else {
+                                format!("`{}` is not defined", x)
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    Some(format!("requires {} ({})", deps, missing.join(", ")))
+                } else {
+                    conflicting_requirement(spec, &id, deps.required_deps())
+                };
+
+                if let Some(reason) = reason {
+                    removed.push(id);
+                    out.push(format!(
+                        "`{}.{}` can never be enabled: {}",
+                        ty.key, field, reason
+                    ));
+                }
+            }
+        }
+
+        if removed.is_empty() {
+            break;
+        }
+
+        for id in removed {
+            reachable.remove(&id);
+        }
+    }
+
+    out
+}
+
+fn conflicting_requirement(
+    spec: &Spec,
+    field: &(TypeKey, String),
+    required: Vec<&Dep>,
+) -> Option<String> {
+    let mut selected: IndexMap<&TypeKey, &str> = IndexMap::new();
+    selected.insert(&field.0, &field.1);
+
+    for dep in required {
+        let is_single = spec
+            .find_type(&dep.ty)
+            .map(|(_, x)| x.is_single)
+            .unwrap_or(false);
+        if !is_single {
+            continue;
+        }
+
+        match selected.get(&dep.ty) {
+            Some(other) if *other != dep.name => {
+                return Some(format!(
+                    "requires both `{}:{}` and `{}`, but `{}` is a single type",
+                    dep.ty, other, dep, dep.ty
+                ))
+            }
+            Some(_) => {}
+            None => {
+                selected.insert(&dep.ty, &dep.name);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+[spec]
+name = "Test"
+
+[spec.types]
+board = { key = "board", single = true }
+radio = { key = "radio" }
+
+[board.dk]
+description = "DK"
+
+[board.custom]
+description = "Custom"
+
+[radio.lora]
+description = "LoRa"
+dependencies = ["board:dk"]
+
+[radio.lora.properties]
+power = { type = "u8", max = 20 }
+
+[radio.ble]
+description = "BLE"
+
+[radio.mesh]
+description = "Mesh"
+dependencies = ["radio:missing"]
+
+[radio.bridge]
+description = "Bridge"
+dependencies = ["radio:mesh"]
+
+[radio.split]
+description = "Split"
+dependencies = ["board:dk", "board:custom"]
+"#;
+
+    fn lints(diagnostics: &[Diagnostic]) -> Vec<(Lint, &str)> {
+        diagnostics
+            .iter()
+            .map(|x| (x.lint, x.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn unreachable_fields_follow_dependencies() {
+        let spec = Spec::parse_str(SPEC).unwrap();
+        let diagnostics = check_spec("test", &spec);
+        let messages = lints(&diagnostics);
+
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|x| x.0 == Lint::UnreachableField));
+        assert!(messages[0]
+            .1
+            .starts_with("`radio.mesh` can never be enabled"));
+        assert!(messages[0].1.contains("`radio:missing` is not defined"));
+        assert!(messages[1]
+            .1
+            .starts_with("`radio.split` can never be enabled"));
+        assert!(messages[1].1.contains("single type"));
+        assert!(messages[2]
+            .1
+            .starts_with("`radio.bridge` can never be enabled"));
+        assert!(messages[2].1.contains("`radio:mesh` can never be enabled"));
+    }
+
+    #[test]
+    fn profile_dependencies_and_constraints() {
+        let spec = Spec::parse_str(SPEC).unwrap();
+        let profile = Profile::parse_str(
+            &spec,
+            r#"
+[profile]
+description = "Test"
+bins = ["app"]
+
+[config]
+board = "custom"
+
+[radio]
+lora = { power = 30 }
+"#,
+        )
+        .unwrap();
+
+        let diagnostics = check_profile("test", &profile);
+        let messages = lints(&diagnostics);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            (
+                Lint::UnsatisfiedDependency,
+                "`radio.lora` requires board:dk"
+            )
+        );
+        assert_eq!(messages[1].0, Lint::PropertyConstraint);
+        assert!(messages[1].1.starts_with("`radio.lora.power`"));
+    }
+
+    #[test]
+    fn unused_fields_are_reported() {
+        let spec = Spec::parse_str(SPEC).unwrap();
+        let profile = Profile::parse_str(
+            &spec,
+            "[profile]\ndescription = \"Test\"\nbins = [\"app\"]\n\
+             [config]\nboard = \"dk\"\n[radio]\nlora = true\n",
+        )
+        .unwrap();
+
+        let diagnostics = check_unused_fields("test", &spec, &[&profile]);
+        let unused = diagnostics
+            .iter()
+            .map(|x| x.message.split('`').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unused,
+            [
+                "board.custom",
+                "radio.ble",
+                "radio.mesh",
+                "radio.bridge",
+                "radio.split"
+            ]
+        );
+    }
+
+    #[test]
+    fn levels_default_and_deny_warnings() {
+        let mut levels = Levels::default();
+        assert_eq!(levels.get(Lint::UnusedField), Level::Warn);
+
+        levels.set(Lint::UnreachableField, Level::Allow);
+        levels.deny_warnings();
+        assert_eq!(levels.get(Lint::UnusedField), Level::Deny);
+        assert_eq!(levels.get(Lint::UnreachableField), Level::Allow);
+        assert_eq!(levels.get(Lint::PropertyConstraint), Level::Deny);
+
+        assert_eq!("unused-field".parse::<Lint>(), Ok(Lint::UnusedField));
+        assert!("unknown".parse::<Lint>().is_err());
+    }
+}
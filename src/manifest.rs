@@ -0,0 +1,170 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::profile::Profile;
+
+/// The name of the manifest written beside a profile's collected binaries.
+pub const FILE_NAME: &str = "manifest.json";
+
+/// A record of a build: the resolved profile, the toolchain and source
+/// revision it was built with, and a hash of each collected binary.
+pub fn build_manifest(
+    root: &Path,
+    name: &str,
+    profile: &Profile,
+    extra_args: &[String],
+    artifacts: &[PathBuf],
+) -> std::io::Result<serde_json::Value> {
+    let config = profile
+        .config
+        .iter()
+        .map(|(ty, fields)| {
+            let fields = fields
+                .iter()
+                .map(|(field, props)| {
+                    let props = props
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), serde_json::Value::from(v)))
+                        .collect::<serde_json::Map<_, _>>();
+                    (field.to_string(), serde_json::Value::from(props))
+                })
+                .collect::<serde_json::Map<_, _>>();
+            (ty.to_string(), serde_json::Value::from(fields))
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    let cfg_flags = profile
+        .cfg_flags()
+        .iter()
+        .map(|x| x.to_rustc_arg())
+        .collect::<Vec<_>>();
+
+    let mut hashes = vec![];
+    for path in artifacts.iter() {
+        hashes.push(json!({
+            "file": path.file_name().and_then(|x| x.to_str()),
+            "sha256": sha256_file(path)?,
+        }));
+    }
+
+    Ok(json!({
+        "profile": name,
+        "description": profile.description,
+        "spec": profile.spec_name,
+        "fingerprint": profile.fingerprint(),
+        "config": config,
+        "cfg_flags": cfg_flags,
+        "features": profile.features,
+        "target": profile.target,
+        "rustflags": profile.rustflags,
+        "cargo_args": profile.cargo_args,
+        "extra_args": extra_args,
+        "toolchain": toolchain_version(),
+        "git": git_revision(root),
+        "artifacts": hashes,
+    }))
+}
+
+/// Checks a previous build's manifest against the profile and the current
+/// state of the sources, returning the previously built binaries if
+/// rebuilding would produce the same thing.
+///
+/// Sources count as unchanged only if both builds were made from the same
+/// commit with no changes to tracked files, with the same toolchain, and the
+/// binaries are still in place.
+pub fn unchanged_artifacts(
+    root: &Path,
+    dist_dir: &Path,
+    profile: &Profile,
+    extra_args: &[String],
+) -> Option<Vec<PathBuf>> {
+    let raw = std::fs::read_to_string(dist_dir.join(FILE_NAME)).ok()?;
+    let previous: serde_json::Value = serde_json::from_str(&raw).ok()?;
+
+    let git = git_revision(root);
+    let is_clean = |x: &serde_json::Value| x.get("dirty") == Some(&json!(false));
+    if !is_clean(&git) || !is_clean(&previous["git"]) || previous["git"]["commit"] != git["commit"]
+    {
+        return None;
+    }
+
+    if previous["fingerprint"] != json!(profile.fingerprint())
+        || previous["extra_args"] != json!(extra_args)
+        || previous["toolchain"] != json!(toolchain_version())
+    {
+        return None;
+    }
+
+    let mut out = vec![];
+    for artifact in previous["artifacts"].as_array()?.iter() {
+        let path = dist_dir.join(artifact["file"].as_str()?);
+        if sha256_file(&path).ok()? != artifact["sha256"].as_str()? {
+            return None;
+        }
+        out.push(path);
+    }
+    Some(out)
+}
+
+/// The SHA-256 of a file's contents, in lowercase hex.
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect())
+}
+
+/// The output of `rustc -V` for the compiler cargo uses, if it can be run.
+fn toolchain_version() -> Option<String> {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let output = Command::new(rustc).arg("-V").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The checked out commit and whether tracked files have uncommitted
+/// changes, or `null` outside of a git repository.
+///
+/// Untracked files are ignored: the dist and target directories are usually
+/// among them, and would otherwise mark every build after the first dirty.
+fn git_revision(root: &Path) -> serde_json::Value {
+    let git = |args: &[&str]| -> Option<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(root)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    match git(&["rev-parse", "HEAD"]) {
+        Some(commit) => json!({
+            "commit": commit,
+            "dirty": git(&["status", "--porcelain", "--untracked-files=no"]).map(|x| !x.is_empty()),
+        }),
+        None => serde_json::Value::Null,
+    }
+}
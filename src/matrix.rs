@@ -0,0 +1,376 @@
+use std::str::FromStr;
+
+use crate::{profile::Profile, spec::Spec};
+
+/// Formats a matrix can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Markdown,
+    Csv,
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "markdown" | "md" => Ok(Format::Markdown),
+            "csv" => Ok(Format::Csv),
+            "html" => Ok(Format::Html),
+            _ => Err(format!(
+                "unknown format `{}`, expected `text`, `markdown`, `csv` or `html`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cell {
+    Enabled,
+    Disabled,
+    /// A property value set by the profile.
+    Value(String),
+    /// A property of a field the profile does not enable, or leaves unset.
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    /// `type.field` for fields, `type.field.property` for properties.
+    pub label: String,
+    pub is_property: bool,
+    pub cells: Vec<Cell>,
+}
+
+/// Every field and property of a spec against a set of profiles using it.
+#[derive(Debug, Clone)]
+pub struct Matrix {
+    pub spec_name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Row>,
+}
+
+impl Matrix {
+    /// Builds a matrix with a row for each field of the spec, followed by a
+    /// row for each of its properties, and a column for each profile.
+    pub fn new(spec_name: &str, spec: &Spec, profiles: &[(&str, &Profile)]) -> Matrix {
+        let mut rows = vec![];
+
+        for (index, fields) in spec.fields.iter() {
+            let ty = &spec.types[index].key;
+            for (field, field_spec) in fields.iter() {
+                rows.push(Row {
+                    label: format!("{}.{}", ty, field),
+                    is_property: false,
+                    cells: profiles
+                        .iter()
+                        .map(|(_, p)| {
+                            if p.is_enabled(ty, field) {
+                                Cell::Enabled
+                            } else {
+                                Cell::Disabled
+                            }
+                        })
+                        .collect(),
+                });
+
+                for prop in field_spec.properties.keys() {
+                    rows.push(Row {
+                        label: format!("{}.{}.{}", ty, field, prop),
+                        is_property: true,
+                        cells: profiles
+                            .iter()
+                            .map(|(_, p)| {
+                                p.config
+                                    .get(ty)
+                                    .and_then(|x| x.get(field))
+                                    .and_then(|x| x.get(prop))
+                                    .map(|x| Cell::Value(x.to_string()))
+                                    .unwrap_or(Cell::Empty)
+                            })
+                            .collect(),
+                    });
+                }
+            }
+        }
+
+        Matrix {
+            spec_name: spec_name.to_string(),
+            columns: profiles.iter().map(|(n, _)| n.to_string()).collect(),
+            rows,
+        }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.to_text(),
+            Format::Markdown => self.to_markdown(),
+            Format::Csv => self.to_csv(),
+            Format::Html => self.to_html(),
+        }
+    }
+
+    fn cells(&self, row: &Row, enabled: &str, disabled: &str) -> Vec<String> {
+        row.cells
+            .iter()
+            .map(|x| match x {
+                Cell::Enabled => enabled.to_string(),
+                Cell::Disabled => disabled.to_string(),
+                Cell::Value(v) => v.to_string(),
+                Cell::Empty => String::new(),
+            })
+            .collect()
+    }
+
+    fn label(row: &Row) -> String {
+        if row.is_property {
+            format!("  {}", row.label)
+        } else {
+            row.label.clone()
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut lines = vec![std::iter::once("Field".to_string())
+            .chain(self.columns.iter().cloned())
+            .collect::<Vec<_>>()];
+        for row in self.rows.iter() {
+            lines.push(
+                std::iter::once(Self::label(row))
+                    .chain(self.cells(row, "yes", "-"))
+                    .collect(),
+            );
+        }
+
+        let widths = (0..=self.columns.len())
+            .map(|i| {
+                lines
+                    .iter()
+                    .map(|x| x[i].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+
+        let mut out = String::new();
+        for line in lines.iter() {
+            let line = line
+                .iter()
+                .zip(widths.iter())
+                .map(|(x, w)| format!("{:<w$}", x, w = w))
+                .collect::<Vec<_>>()
+                .join("  ");
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+
+    fn to_markdown(&self) -> String {
+        let escape = |x: &str| x.replace('|', "\\|");
+        let columns = self.columns.iter().map(|x| escape(x)).collect::<Vec<_>>();
+        let mut out = format!("| Field | {} |\n", columns.join(" | "));
+        out.push_str(&format!("|---|{}\n", "---|".repeat(self.columns.len())));
+        for row in self.rows.iter() {
+            let label = if row.is_property {
+                format!("↳ `{}`", row.label)
+            } else {
+                format!("`{}`", row.label)
+            };
+            let cells = self
+                .cells(row, "✓", "")
+                .iter()
+                .map(|x| escape(x))
+                .collect::<Vec<_>>();
+            out.push_str(&format!("| {} | {} |\n", label, cells.join(" | ")));
+        }
+        out
+    }
+
+    fn to_csv(&self) -> String {
+        let escape = |x: &str| {
+            if x.contains([',', '"', '\n']) {
+                format!("\"{}\"", x.replace('"', "\"\""))
+            } else {
+                x.to_string()
+            }
+        };
+        let mut out = std::iter::once("field")
+            .chain(self.columns.iter().map(String::as_str))
+            .map(escape)
+            .collect::<Vec<_>>()
+            .join(",");
+        out.push('\n');
+        for row in self.rows.iter() {
+            let line = std::iter::once(row.label.clone())
+                .chain(self.cells(row, "yes", "no"))
+                .map(|x| escape(&x))
+                .collect::<Vec<_>>()
+                .join(",");
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+
+    fn to_html(&self) -> String {
+        let mut out =
+            String::from("<table class=\"pbuild-matrix\">\n  <thead>\n    <tr><th>Field</th>");
+        for column in self.columns.iter() {
+            out.push_str(&format!("<th>{}</th>", html_escape(column)));
+        }
+        out.push_str("</tr>\n  </thead>\n  <tbody>\n");
+        for row in self.rows.iter() {
+            let class = if row.is_property { "property" } else { "field" };
+            out.push_str(&format!(
+                "    <tr class=\"{}\"><th><code>{}</code></th>",
+                class,
+                html_escape(&row.label)
+            ));
+            for cell in row.cells.iter() {
+                out.push_str(&match cell {
+                    Cell::Enabled => "<td class=\"enabled\">✓</td>".to_string(),
+                    Cell::Disabled => "<td class=\"disabled\"></td>".to_string(),
+                    Cell::Value(v) => format!("<td class=\"value\">{}</td>", html_escape(v)),
+                    Cell::Empty => "<td></td>".to_string(),
+                });
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("  </tbody>\n</table>\n");
+        out
+    }
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders several matrices as one document, with a heading per spec when
+/// there is more than one.
+pub fn render_all(matrices: &[Matrix], format: Format) -> String {
+    let headed = matrices.len() > 1;
+    let mut out = String::new();
+
+    if format == Format::Html {
+        out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Profile matrix</title>\n</head>\n<body>\n");
+    }
+
+    for (i, matrix) in matrices.iter().enumerate() {
+        if headed {
+            match format {
+                Format::Text => {
+                    if i > 0 {
+                        out.push('\n');
+                    }
+                    out.push_str(&format!("Spec `{}`:\n", matrix.spec_name));
+                }
+                Format::Markdown => {
+                    if i > 0 {
+                        out.push('\n');
+                    }
+                    out.push_str(&format!("## {}\n\n", matrix.spec_name));
+                }
+                Format::Html => {
+                    out.push_str(&format!("<h2>{}</h2>\n", html_escape(&matrix.spec_name)))
+                }
+                Format::Csv => {}
+            }
+        }
+        out.push_str(&matrix.render(format));
+    }
+
+    if format == Format::Html {
+        out.push_str("</body>\n</html>\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> Matrix {
+        let spec = Spec::parse_str(
+            "[spec]\nname = \"main\"\n\
+             [spec.types]\nradio = { key = \"radio\" }\n\
+             [radio.lora]\ndescription = \"LoRa\"\n\
+             [radio.lora.properties]\nregion = { type = \"string\" }\n\
+             [radio.ble]\ndescription = \"BLE\"\n",
+        )
+        .unwrap();
+        let profile = |s: &str| {
+            let s = format!(
+                "[profile]\ndescription = \"P\"\nbins = [\"app\"]\n[config]\n{}",
+                s
+            );
+            Profile::parse_str(&spec, &s).unwrap()
+        };
+        let eu = profile("[radio]\nlora = { region = \"eu, \\\"868\\\" | <x>\" }\n");
+        let ble = profile("[radio]\nble = true\n");
+        Matrix::new("main", &spec, &[("eu|lora", &eu), ("ble", &ble)])
+    }
+
+    #[test]
+    fn renders_text() {
+        assert_eq!(
+            matrix().render(Format::Text),
+            "Field                eu|lora          ble\n\
+             radio.lora           yes              -\n\
+             \x20 radio.lora.region  eu, \"868\" | <x>\n\
+             radio.ble            -                yes\n"
+        );
+    }
+
+    #[test]
+    fn renders_markdown_with_escaped_pipes() {
+        assert_eq!(
+            matrix().render(Format::Markdown),
+            "| Field | eu\\|lora | ble |\n\
+             |---|---|---|\n\
+             | `radio.lora` | ✓ |  |\n\
+             | ↳ `radio.lora.region` | eu, \"868\" \\| <x> |  |\n\
+             | `radio.ble` |  | ✓ |\n"
+        );
+    }
+
+    #[test]
+    fn renders_csv_with_quoting() {
+        assert_eq!(
+            matrix().render(Format::Csv),
+            "field,eu|lora,ble\n\
+             radio.lora,yes,no\n\
+             radio.lora.region,\"eu, \"\"868\"\" | <x>\",\n\
+             radio.ble,no,yes\n"
+        );
+    }
+
+    #[test]
+    fn renders_html_with_escaping() {
+        let html = matrix().render(Format::Html);
+        assert!(html.contains("<th>eu|lora</th><th>ble</th>"), "{}", html);
+        assert!(
+            html.contains(
+                "<tr class=\"property\"><th><code>radio.lora.region</code></th>\
+                 <td class=\"value\">eu, &quot;868&quot; | &lt;x&gt;</td><td></td></tr>"
+            ),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(
+                "<tr class=\"field\"><th><code>radio.ble</code></th>\
+                 <td class=\"disabled\"></td><td class=\"enabled\">✓</td></tr>"
+            ),
+            "{}",
+            html
+        );
+    }
+}
@@ -1,7 +1,11 @@
-use std::{fmt::Display, path::Path};
+use std::{fmt::Display, path::Path, process::Command};
 
-use crate::spec::{FieldKey, Spec, TypeIndex, TypeKey, Value};
+use crate::{
+    cfg::{self, CfgFlag},
+    spec::{DependencyOp, FieldKey, Spec, TypeIndex, TypeKey, Value},
+};
 use indexmap::IndexMap;
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -13,15 +17,48 @@ pub enum Error {
 
     #[error("Either [profile.bins] or [profile.libs] must be provided.")]
     NoBinsOrLibs,
+
+    #[error("`{0}` field in [profile] is not of type `{1}`.")]
+    InvalidFieldType(&'static str, &'static str),
+
+    #[error("[profile] is missing a `{0}` field.")]
+    MissingField(&'static str),
+
+    #[error("[config] section not found or wrong type.")]
+    ConfigMissing,
+
+    #[error("Type `{0}` is not defined in the spec.")]
+    UnknownType(String),
+
+    #[error("Field `{0}.{1}` is not defined in the spec.")]
+    UnknownField(String, String),
+
+    #[error("Property `{0}.{1}.{2}` is not defined in the spec.")]
+    UnknownProperty(String, String, String),
+
+    #[error("Property `{0}.{1}.{2}` is not of type `{3}`.")]
+    InvalidPropertyValue(String, String, String, &'static str),
+
+    #[error("Value for `{0}` must be {1}.")]
+    InvalidValue(String, &'static str),
 }
 
+/// The spec used by profiles that do not set `[profile] spec`.
+pub const DEFAULT_SPEC: &str = "main";
+
 #[derive(Debug, Clone)]
 pub struct Profile {
     pub spec: Spec,
+    pub spec_name: String,
     pub description: String,
+    pub tags: Vec<String>,
     pub bins: Vec<String>,
     pub libs: Vec<String>,
     pub features: Vec<String>,
+    pub target: Option<String>,
+    pub rustflags: Vec<String>,
+    pub cargo_args: Vec<String>,
+    pub env: IndexMap<String, String>,
     pub config: IndexMap<TypeKey, IndexMap<FieldKey, IndexMap<String, Value>>>,
 }
 
@@ -31,21 +68,53 @@ impl Profile {
         Self::parse_str(spec, &std::fs::read_to_string(path)?)
     }
 
+    /// Reads the name of the spec a profile uses without parsing the rest of
+    /// the profile.
+    pub fn spec_name(s: &str) -> Result<String, Error> {
+        let raw: toml::map::Map<String, toml::Value> = toml::from_str(s)?;
+        Self::parse_spec_name(&raw)
+    }
+
+    /// Reads a profile's tags without parsing the rest of the profile.
+    pub fn tags(s: &str) -> Result<Vec<String>, Error> {
+        let raw: toml::map::Map<String, toml::Value> = toml::from_str(s)?;
+        Self::parse_string_array(&raw, "tags")
+    }
+
+    fn parse_spec_name(raw: &toml::map::Map<String, toml::Value>) -> Result<String, Error> {
+        match raw.get("profile").and_then(|x| x.get("spec")) {
+            Some(v) => v
+                .as_str()
+                .map(|x| x.to_string())
+                .ok_or(Error::InvalidFieldType("spec", "string")),
+            None => Ok(DEFAULT_SPEC.to_string()),
+        }
+    }
+
     fn parse_config(
         spec: &Spec,
         ty: TypeKey,
         v: &toml::Value,
         map: &mut IndexMap<FieldKey, IndexMap<String, Value>>,
-    ) {
-        let (index, _tyspec) = spec.types.iter().find(|(_, x)| x.key == ty).unwrap();
+    ) -> Result<(), Error> {
+        let (index, _tyspec) = spec
+            .types
+            .iter()
+            .find(|(_, x)| x.key == ty)
+            .ok_or_else(|| Error::UnknownType(ty.to_string()))?;
         match v {
             toml::Value::String(s) => {
                 let s = FieldKey::new(s.into());
-                let _field_spec = spec.fields.get(index).unwrap().get(&s).unwrap();
+                if !spec.fields.get(index).is_some_and(|x| x.contains_key(&s)) {
+                    return Err(Error::UnknownField(ty.to_string(), s.to_string()));
+                }
                 map.insert(s, IndexMap::<String, Value>::new());
+                Ok(())
             }
-            toml::Value::Table(_t) => todo!("Table values not supported here yet"),
-            _ => panic!("Unsupported value"),
+            _ => Err(Error::InvalidValue(
+                format!("config.{}", ty),
+                "the name of a field",
+            )),
         }
     }
 
@@ -53,131 +122,241 @@ impl Profile {
     pub fn parse_str(spec: &Spec, s: &str) -> Result<Profile, Error> {
         let raw: toml::map::Map<String, toml::Value> = toml::from_str(s)?;
 
-        let bins = raw
-            .get("profile")
-            .and_then(|x| x.get("bins"))
-            .and_then(|x| x.as_array())
-            .map(|x| {
-                x.iter()
-                    .map(|x| x.as_str().unwrap().to_string())
-                    .collect::<Vec<String>>()
-            })
-            .unwrap_or_default();
+        let spec_name = Self::parse_spec_name(&raw)?;
 
-        let libs = raw
-            .get("profile")
-            .and_then(|x| x.get("libs"))
-            .and_then(|x| x.as_array())
-            .map(|x| {
-                x.iter()
-                    .map(|x| x.as_str().unwrap().to_string())
-                    .collect::<Vec<String>>()
-            })
-            .unwrap_or_default();
+        let bins = Self::parse_string_array(&raw, "bins")?;
+        let libs = Self::parse_string_array(&raw, "libs")?;
 
         if bins.is_empty() && libs.is_empty() {
             return Err(Error::NoBinsOrLibs);
         }
 
-        let features = raw
-            .get("profile")
-            .and_then(|x| x.get("features"))
-            .and_then(|x| x.as_array())
-            .map(|x| {
-                x.iter()
-                    .map(|x| x.as_str().unwrap().to_string())
-                    .collect::<Vec<String>>()
-            })
-            .unwrap_or_default();
+        let features = Self::parse_string_array(&raw, "features")?;
+
+        let target = match raw.get("profile").and_then(|x| x.get("target")) {
+            Some(v) => Some(
+                v.as_str()
+                    .ok_or(Error::InvalidFieldType("target", "string"))?
+                    .to_string(),
+            ),
+            None => None,
+        };
+
+        let rustflags = Self::parse_string_array(&raw, "rustflags")?;
+        let cargo_args = Self::parse_string_array(&raw, "cargo_args")?;
+
+        let mut env = IndexMap::new();
+        for (k, v) in raw
+            .get("env")
+            .and_then(|x| x.as_table())
+            .into_iter()
+            .flatten()
+        {
+            let v = match v {
+                toml::Value::String(s) => s.to_string(),
+                toml::Value::Integer(x) => x.to_string(),
+                toml::Value::Boolean(x) => x.to_string(),
+                _ => {
+                    return Err(Error::InvalidValue(
+                        format!("env.{}", k),
+                        "a string, integer or boolean",
+                    ))
+                }
+            };
+            env.insert(k.to_string(), v);
+        }
 
         let description = raw
             .get("profile")
             .and_then(|x| x.get("description"))
-            .and_then(|x| x.as_str())
-            .unwrap()
+            .ok_or(Error::MissingField("description"))?
+            .as_str()
+            .ok_or(Error::InvalidFieldType("description", "string"))?
             .to_string();
 
+        let tags = Self::parse_string_array(&raw, "tags")?;
+
         let mut config: IndexMap<TypeKey, IndexMap<FieldKey, IndexMap<String, Value>>> =
             IndexMap::new();
 
-        raw.get("config")
+        for (k, v) in raw
+            .get("config")
             .and_then(|x| x.as_table())
-            .unwrap()
+            .ok_or(Error::ConfigMissing)?
             .iter()
-            .for_each(|(k, v)| {
-                let k = TypeKey::new(k.into());
-                let entry = config.entry(k.clone()).or_default();
-                Self::parse_config(spec, k, v, entry);
-            });
-
-        raw.iter()
-            .filter(|(k, _)| *k != "profile" && *k != "config")
-            .for_each(|(k, v)| {
-                let type_index = TypeIndex::new(k.into());
-                let type_key = spec.types.get(&type_index).unwrap().key.clone();
-
-                v.as_table().unwrap().iter().for_each(|(xk, xv)| {
-                    let xk = FieldKey::new(xk.into());
-                    match xv {
-                        toml::Value::Boolean(x) => {
-                            let _field_spec =
-                                spec.fields.get(&type_index).unwrap().get(&xk).unwrap();
-                            if *x {
-                                config
-                                    .entry(type_key.clone())
-                                    .or_default()
-                                    .entry(xk.clone())
-                                    .or_default();
-                            }
-                        }
-                        toml::Value::Table(t) => {
-                            let field_spec =
-                                spec.fields.get(&type_index).unwrap().get(&xk).unwrap();
-
-                            let mut props = t
-                                .iter()
-                                .map(|(k, v)| {
-                                    let prop_spec = field_spec.properties.get(k).unwrap();
-                                    let v = Value::new(prop_spec.ty, v)
-                                        .unwrap_or_else(|| Value::default(prop_spec.ty));
-                                    (k.to_string(), v)
-                                })
-                                .collect::<IndexMap<_, _>>();
-
-                            field_spec.properties.iter().for_each(|(k, v)| {
-                                if let Some(default) = v.default.as_ref() {
-                                    if !props.contains_key(k) {
-                                        props.insert(k.into(), default.clone());
-                                    }
-                                }
-                            });
+        {
+            let k = TypeKey::new(k.into());
+            let entry = config.entry(k.clone()).or_default();
+            Self::parse_config(spec, k, v, entry)?;
+        }
 
-                            let m = config
+        for (k, v) in raw
+            .iter()
+            .filter(|(k, _)| *k != "profile" && *k != "config" && *k != "env")
+        {
+            let type_index = TypeIndex::new(k.into());
+            let type_key = spec
+                .types
+                .get(&type_index)
+                .ok_or_else(|| Error::UnknownType(k.to_string()))?
+                .key
+                .clone();
+
+            let fields = v
+                .as_table()
+                .ok_or_else(|| Error::InvalidValue(k.to_string(), "a table"))?;
+
+            for (xk, xv) in fields.iter() {
+                let xk = FieldKey::new(xk.into());
+                let field_spec = spec
+                    .fields
+                    .get(&type_index)
+                    .and_then(|x| x.get(&xk))
+                    .ok_or_else(|| Error::UnknownField(k.to_string(), xk.to_string()))?;
+
+                match xv {
+                    toml::Value::Boolean(x) => {
+                        if *x {
+                            config
                                 .entry(type_key.clone())
                                 .or_default()
                                 .entry(xk.clone())
                                 .or_default();
-                            *m = props;
                         }
-                        _ => panic!("No."),
                     }
-                });
-            });
+                    toml::Value::Table(t) => {
+                        let mut props = t
+                            .iter()
+                            .map(|(pk, pv)| {
+                                let prop_spec = field_spec.properties.get(pk).ok_or_else(|| {
+                                    Error::UnknownProperty(
+                                        k.to_string(),
+                                        xk.to_string(),
+                                        pk.to_string(),
+                                    )
+                                })?;
+                                let pv = Value::new(prop_spec.ty, pv).ok_or_else(|| {
+                                    Error::InvalidPropertyValue(
+                                        k.to_string(),
+                                        xk.to_string(),
+                                        pk.to_string(),
+                                        prop_spec.ty.as_str(),
+                                    )
+                                })?;
+                                Ok((pk.to_string(), pv))
+                            })
+                            .collect::<Result<IndexMap<_, _>, Error>>()?;
+
+                        field_spec.properties.iter().for_each(|(k, v)| {
+                            if let Some(default) = v.default.as_ref() {
+                                if !props.contains_key(k) {
+                                    props.insert(k.into(), default.clone());
+                                }
+                            }
+                        });
+
+                        let m = config
+                            .entry(type_key.clone())
+                            .or_default()
+                            .entry(xk.clone())
+                            .or_default();
+                        *m = props;
+                    }
+                    _ => {
+                        return Err(Error::InvalidValue(
+                            format!("{}.{}", k, xk),
+                            "a boolean or a table of properties",
+                        ))
+                    }
+                }
+            }
+        }
 
         Ok(Profile {
             bins,
             libs,
             features,
+            target,
+            rustflags,
+            cargo_args,
+            env,
             spec: spec.clone(),
+            spec_name,
             description,
+            tags,
             config,
         })
     }
 
-    pub fn cfg_flags_map(&self) -> IndexMap<String, Value> {
-        use heck::SnakeCase;
+    fn parse_string_array(
+        raw: &toml::map::Map<String, toml::Value>,
+        key: &'static str,
+    ) -> Result<Vec<String>, Error> {
+        match raw.get("profile").and_then(|x| x.get(key)) {
+            Some(v) => v
+                .as_array()
+                .and_then(|x| {
+                    x.iter()
+                        .map(|x| x.as_str().map(|x| x.to_string()))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .ok_or(Error::InvalidFieldType(key, "array<string>")),
+            None => Ok(vec![]),
+        }
+    }
 
-        let mut out = IndexMap::new();
+    /// The number of fields enabled across all types.
+    pub fn enabled_field_count(&self) -> usize {
+        self.config.values().map(|x| x.len()).sum()
+    }
+
+    pub fn is_enabled(&self, ty: &TypeKey, field: &str) -> bool {
+        self.config
+            .get(ty)
+            .is_some_and(|x| x.keys().any(|k| k.as_str() == field))
+    }
+
+    /// Enabled fields whose dependencies are not satisfied by this profile.
+    pub fn unsatisfied_dependencies(&self) -> Vec<(&TypeKey, &FieldKey, &DependencyOp)> {
+        let mut out = vec![];
+        for (ty, fields) in self.config.iter() {
+            for field in fields.keys() {
+                let deps = match self.spec.field(ty, field) {
+                    Some(v) => &*v.dependencies,
+                    None => continue,
+                };
+                if !deps.is_satisfied(|dep| self.is_enabled(&dep.ty, &dep.name)) {
+                    out.push((ty, field, deps));
+                }
+            }
+        }
+        out
+    }
+
+    /// Property values violating the constraints declared in the spec, as
+    /// `(type.field.property, reason)` pairs.
+    pub fn constraint_violations(&self) -> Vec<(String, String)> {
+        let mut out = vec![];
+        for (ty, fields) in self.config.iter() {
+            for (field, props) in fields.iter() {
+                let field_spec = match self.spec.field(ty, field) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                for (prop, value) in props.iter() {
+                    if let Some(Err(e)) = field_spec.properties.get(prop).map(|x| x.check(value)) {
+                        out.push((format!("{}.{}.{}", ty, field, prop), e));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// The cfg names and values generated from the profile's config, in
+    /// order. Key/value types may produce the same name more than once.
+    fn cfg_values(&self) -> Vec<(String, Value)> {
+        let mut out = vec![];
         for (ty, v) in self.config.iter() {
             let tyspec = self
                 .spec
@@ -188,17 +367,13 @@ impl Profile {
                 .1;
 
             for (ahh, brr) in v {
-                if tyspec.is_single {
-                    out.insert(
-                        format!("{}", ty).to_snake_case(),
-                        Value::String(ahh.to_string()),
-                    );
+                if tyspec.is_key_value() {
+                    out.push((tyspec.cfg_name(), Value::String(ahh.to_string())));
                 } else {
-                    out.insert(format!("{}_{}", ty, ahh).to_snake_case(), Value::Bool(true));
+                    out.push((tyspec.field_cfg_name(ahh), Value::Bool(true)));
                 }
                 for (prop_key, prop_val) in brr {
-                    let cfg_key = format!("{}_{}_{}", ty, ahh, prop_key).to_snake_case();
-                    out.insert(cfg_key, prop_val.clone());
+                    out.push((tyspec.property_cfg_name(ahh, prop_key), prop_val.clone()));
                 }
             }
         }
@@ -206,32 +381,215 @@ impl Profile {
         out
     }
 
+    /// The cfg names and values generated from the profile's config, by
+    /// name. A key/value type with several enabled fields only keeps the
+    /// last of them.
+    #[deprecated(note = "use `Profile::cfg_flags`, which keeps every value of key/value types")]
+    pub fn cfg_flags_map(&self) -> IndexMap<String, Value> {
+        self.cfg_values().into_iter().collect()
+    }
+
+    /// The cfg flags generated from the profile's config, in order.
+    /// Key/value types may produce the same name more than once.
+    pub fn cfg_flags(&self) -> Vec<CfgFlag> {
+        self.cfg_values()
+            .into_iter()
+            .filter_map(|(name, value)| CfgFlag::from_value(name, &value))
+            .collect()
+    }
+
+    /// `--cfg` arguments for rustc, unquoted. Use [`cfg::to_shell`] to
+    /// display them.
     pub fn rustc_cfg_flags(&self) -> Vec<String> {
-        let map = self.cfg_flags_map();
-        let mut out = vec![];
+        cfg::rustc_args(&self.cfg_flags())
+    }
 
-        for (k, v) in map {
-            if matches!(v, Value::Bool(false)) {
-                continue;
-            }
+    /// All flags passed to rustc, in order: the profile's own `rustflags`,
+    /// the generated cfg flags and the spec's `--check-cfg` declarations.
+    pub fn rustc_flags(&self) -> Vec<String> {
+        self.rustflags
+            .iter()
+            .cloned()
+            .chain(self.rustc_cfg_flags())
+            .chain(self.spec.check_cfg_flags())
+            .collect()
+    }
+
+    /// `cargo:rustc-cfg` directives for applying the profile's cfg flags
+    /// from a build script, preceded by `cargo:rustc-check-cfg` directives
+    /// declaring every cfg the spec could produce.
+    pub fn cargo_cfg_directives(&self) -> Vec<String> {
+        self.spec
+            .check_cfg_flags()
+            .iter()
+            .filter(|x| *x != "--check-cfg")
+            .map(|x| format!("cargo:rustc-check-cfg={}", x))
+            .chain(self.cfg_flags().iter().map(|x| x.to_cargo_directive()))
+            .collect()
+    }
+
+    /// Creates one `cargo <subcommand>` invocation per entry in
+    /// [`Profile::cargo_flags`], with the profile's environment and rustc
+    /// flags applied.
+    ///
+    /// The profile's flags are passed in `CARGO_ENCODED_RUSTFLAGS`, which
+    /// takes precedence over every other source, so the flags cargo would
+    /// otherwise use are kept and precede them: those already set in the
+    /// environment or, failing that, those from Cargo config files. A
+    /// `RUSTFLAGS` or `CARGO_ENCODED_RUSTFLAGS` in the profile's `[env]` is
+    /// added after those. Config flags that cannot be carried over are
+    /// reported as warnings.
+    pub fn cargo_commands(&self, subcommand: &str, extra_args: &[String]) -> Vec<Command> {
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+        let rustflags = self.merge_rustflags(self.base_rustflags());
+        let rustflags = cfg::to_encoded_rustflags(&rustflags);
+
+        let env = self.env.iter().filter(|(k, _)| !is_rustflags_var(k));
+
+        self.cargo_flags()
+            .into_iter()
+            .map(|flags| {
+                let mut cmd = Command::new(&cargo);
+                cmd.arg(subcommand)
+                    .args(flags)
+                    .args(&self.cargo_args)
+                    .args(extra_args)
+                    .envs(env.clone())
+                    .env_remove("RUSTFLAGS")
+                    .env("CARGO_ENCODED_RUSTFLAGS", &rustflags);
+                if let Some(target) = self.target.as_ref() {
+                    cmd.env("CARGO_BUILD_TARGET", target);
+                }
+                cmd
+            })
+            .collect()
+    }
 
-            out.push("--cfg".into());
-            out.push(match v {
-                Value::String(x) => format!("'{}={:?}'", k, x),
-                Value::Bool(_) => format!("'{}'", k),
-                Value::U8(x) => format!("'{}={}'", k, x),
-                Value::U16(x) => format!("'{}={}'", k, x),
-                Value::U32(x) => format!("'{}={}'", k, x),
-                Value::U64(x) => format!("'{}={}'", k, x),
-                Value::I8(x) => format!("'{}={}'", k, x),
-                Value::I16(x) => format!("'{}={}'", k, x),
-                Value::I32(x) => format!("'{}={}'", k, x),
-                Value::I64(x) => format!("'{}={}'", k, x),
-                #[cfg(feature = "uuid")]
-                Value::Uuid(x) => format!("'{}=\"{}\"'", k, x.to_hyphenated_ref().to_string()),
-            });
+    /// The rustc flags cargo would use without the profile: those set in the
+    /// environment or, failing that, those from Cargo config files. Config
+    /// flags that cannot be carried over are reported as warnings.
+    fn base_rustflags(&self) -> Vec<String> {
+        if let Some(flags) = cfg::ambient_rustflags() {
+            return flags;
         }
-        out
+
+        let dir = std::env::current_dir().unwrap_or_default();
+        let (flags, ignored) = cfg::config_rustflags(&dir, self.target.as_deref());
+        for entry in ignored {
+            eprintln!(
+                "warning: {} is overridden by the profile's flags and will not apply",
+                entry
+            );
+        }
+        flags
+    }
+
+    /// `base` followed by any `CARGO_ENCODED_RUSTFLAGS` and `RUSTFLAGS` in the
+    /// profile's `[env]`, then the profile's own flags.
+    fn merge_rustflags(&self, mut base: Vec<String>) -> Vec<String> {
+        if let Some(x) = self.env.get("CARGO_ENCODED_RUSTFLAGS") {
+            base.extend(cfg::split_encoded_rustflags(x));
+        }
+        if let Some(x) = self.env.get("RUSTFLAGS") {
+            base.extend(x.split_whitespace().map(str::to_string));
+        }
+        base.extend(self.rustc_flags());
+        base
+    }
+
+    /// Environment variables describing the profile, for use outside of
+    /// `cargo pbuild`: rustc flags, build target, features and the profile's
+    /// own `[env]` table.
+    ///
+    /// The rustc flags are merged the same way as for
+    /// [`Profile::cargo_commands`]. They are exported as `RUSTFLAGS`, or as
+    /// `CARGO_ENCODED_RUSTFLAGS` if that is already set or appears in
+    /// `[env]`, since cargo would otherwise ignore `RUSTFLAGS`.
+    ///
+    /// `PBUILD_PROFILE` is left out: once exported it would take precedence
+    /// over the profile chosen with `cargo pbuild use`.
+    pub fn env_vars(&self) -> Result<IndexMap<String, String>, cfg::Error> {
+        let encoded = std::env::var_os("CARGO_ENCODED_RUSTFLAGS").is_some();
+        self.env_vars_from(self.base_rustflags(), encoded)
+    }
+
+    fn env_vars_from(
+        &self,
+        base_rustflags: Vec<String>,
+        encoded: bool,
+    ) -> Result<IndexMap<String, String>, cfg::Error> {
+        let rustflags = self.merge_rustflags(base_rustflags);
+
+        let mut out = IndexMap::new();
+        if encoded || self.env.contains_key("CARGO_ENCODED_RUSTFLAGS") {
+            out.insert(
+                "CARGO_ENCODED_RUSTFLAGS".to_string(),
+                cfg::to_encoded_rustflags(&rustflags),
+            );
+        } else {
+            out.insert("RUSTFLAGS".to_string(), cfg::to_rustflags(&rustflags)?);
+        }
+        if let Some(target) = self.target.as_ref() {
+            out.insert("CARGO_BUILD_TARGET".to_string(), target.to_string());
+        }
+        out.insert("PBUILD_FEATURES".to_string(), self.features.join(","));
+        for (k, v) in self.env.iter().filter(|(k, _)| !is_rustflags_var(k)) {
+            out.insert(k.to_string(), v.to_string());
+        }
+        Ok(out)
+    }
+
+    /// A SHA-256 over everything that affects what the profile builds, in
+    /// lowercase hex.
+    ///
+    /// The description and tags are left out, and order is ignored wherever
+    /// it has no effect on the build, so two profiles with the same
+    /// fingerprint build the same thing.
+    pub fn fingerprint(&self) -> String {
+        fn sorted<'a>(x: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+            let mut x = x.collect::<Vec<_>>();
+            x.sort();
+            x.dedup();
+            x
+        }
+
+        let mut config = self
+            .config
+            .iter()
+            .flat_map(|(ty, fields)| {
+                fields.iter().map(move |(field, props)| {
+                    let mut props = props
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), serde_json::Value::from(v)))
+                        .collect::<Vec<_>>();
+                    props.sort_by(|a, b| a.0.cmp(&b.0));
+                    (ty.to_string(), field.to_string(), props)
+                })
+            })
+            .collect::<Vec<_>>();
+        config.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+        let mut env = self.env.iter().collect::<Vec<_>>();
+        env.sort();
+
+        let data = serde_json::json!({
+            "spec": self.spec_name,
+            "check_cfg": self.spec.check_cfg_flags(),
+            "config": config,
+            "bins": sorted(self.bins.iter()),
+            "libs": sorted(self.libs.iter()),
+            "features": sorted(self.features.iter()),
+            "target": self.target,
+            "rustflags": self.rustflags,
+            "cargo_args": self.cargo_args,
+            "env": env,
+        });
+
+        Sha256::digest(data.to_string().as_bytes())
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect()
     }
 
     pub fn cargo_flags(&self) -> Vec<Vec<String>> {
@@ -239,8 +597,8 @@ impl Profile {
 
         for bin in self.bins.iter() {
             let mut o = vec![];
-            if bin.contains("/") {
-                let mut chunks = bin.split("/");
+            if bin.contains('/') {
+                let mut chunks = bin.split('/');
                 o.push("-p".into());
                 o.push(chunks.next().unwrap().to_string());
                 o.push("--bin".into());
@@ -251,18 +609,16 @@ impl Profile {
             }
             if !self.features.is_empty() {
                 o.push("--features".into());
-                o.push(format!("\"{}\"", self.features.join("\",\"")));
+                o.push(self.features.join(","));
             }
             out.push(o);
         }
 
         for lib in self.libs.iter() {
-            let mut o = vec![];
-            o.push("--lib".into());
-            o.push(lib.to_string());
+            let mut o = vec!["-p".to_string(), lib.to_string(), "--lib".into()];
             if !self.features.is_empty() {
                 o.push("--features".into());
-                o.push(format!("\"{}\"", self.features.join("\",\"")));
+                o.push(self.features.join(","));
             }
             out.push(o);
         }
@@ -276,6 +632,16 @@ impl Display for Profile {
         f.write_str(&self.description)?;
         f.write_str("\n\n")?;
 
+        f.write_fmt(format_args!("Spec: {}\n", self.spec_name))?;
+        f.write_fmt(format_args!("Fingerprint: {}\n", self.fingerprint()))?;
+        if !self.tags.is_empty() {
+            f.write_fmt(format_args!("Tags: {}\n", self.tags.join(", ")))?;
+        }
+        if let Some(target) = self.target.as_ref() {
+            f.write_fmt(format_args!("Target: {}\n", target))?;
+        }
+        f.write_str("\n")?;
+
         let mut add_nl = false;
 
         if !self.bins.is_empty() {
@@ -321,16 +687,135 @@ impl Display for Profile {
 
         f.write_str("Rust compiler flags:\n")?;
         f.write_str("  ")?;
-        f.write_str(&self.rustc_cfg_flags().join(" "))?;
+        let rustc_flags = self
+            .rustflags
+            .iter()
+            .cloned()
+            .chain(self.rustc_cfg_flags())
+            .collect::<Vec<_>>();
+        f.write_str(&cfg::to_shell(&rustc_flags))?;
         f.write_str("\n\n")?;
 
         f.write_str("Cargo flags:\n")?;
         for line in self.cargo_flags() {
             f.write_str("  ")?;
-            f.write_str(&line.join(" "))?;
+            let line = line
+                .into_iter()
+                .chain(self.cargo_args.iter().cloned())
+                .collect::<Vec<_>>();
+            f.write_str(&cfg::to_shell(&line))?;
             f.write_str("\n")?;
         }
 
+        if !self.env.is_empty() {
+            f.write_str("\nEnvironment:\n")?;
+            for (k, v) in self.env.iter() {
+                f.write_fmt(format_args!("  {}={}\n", k, v))?;
+            }
+        }
+
         Ok(())
     }
 }
+
+/// Whether an `[env]` variable is merged into the profile's rustc flags
+/// rather than passed through.
+fn is_rustflags_var(name: &str) -> bool {
+    name == "RUSTFLAGS" || name == "CARGO_ENCODED_RUSTFLAGS"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> Spec {
+        Spec::parse_str("[spec]\nname = \"main\"\n[spec.types]\n").unwrap()
+    }
+
+    fn profile(extra: &str) -> Result<Profile, Error> {
+        let s = format!(
+            "[profile]\ndescription = \"Test\"\nbins = [\"app\"]\n[config]\n{}",
+            extra
+        );
+        Profile::parse_str(&spec(), &s)
+    }
+
+    #[test]
+    fn cargo_cfg_directives_declare_then_set_cfgs() {
+        let spec = Spec::parse_str(
+            "[spec]\nname = \"main\"\n\
+             [spec.types]\nboard = { key = \"board\", single = true }\n\
+             [board.dk]\ndescription = \"DK\"\n",
+        )
+        .unwrap();
+        let profile = Profile::parse_str(
+            &spec,
+            "[profile]\ndescription = \"Test\"\nbins = [\"app\"]\n[config]\nboard = \"dk\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            profile.cargo_cfg_directives(),
+            [
+                "cargo:rustc-check-cfg=cfg(board,values(\"dk\"))",
+                "cargo:rustc-cfg=board=\"dk\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn env_vars_merge_rustflags_from_env_after_inherited_flags() {
+        let profile =
+            profile("[env]\nRUSTFLAGS = \"-C target-cpu=native\"\nDEFMT_LOG = \"info\"\n").unwrap();
+        let base = vec!["-C".to_string(), "link-arg=-Tlink.x".to_string()];
+        let vars = profile.env_vars_from(base.clone(), false).unwrap();
+        assert_eq!(
+            vars.keys().collect::<Vec<_>>(),
+            ["RUSTFLAGS", "PBUILD_FEATURES", "DEFMT_LOG"]
+        );
+        assert_eq!(
+            vars["RUSTFLAGS"],
+            "-C link-arg=-Tlink.x -C target-cpu=native"
+        );
+
+        let vars = profile.env_vars_from(base, true).unwrap();
+        assert!(!vars.contains_key("RUSTFLAGS"));
+        assert_eq!(
+            vars["CARGO_ENCODED_RUSTFLAGS"],
+            "-C\x1flink-arg=-Tlink.x\x1f-C\x1ftarget-cpu=native"
+        );
+    }
+
+    #[test]
+    fn env_vars_merge_encoded_rustflags_from_env() {
+        let profile = profile("[env]\nCARGO_ENCODED_RUSTFLAGS = \"--cfg\\u001fa\"\n").unwrap();
+        let vars = profile.env_vars_from(vec![], false).unwrap();
+        assert_eq!(
+            vars.keys().collect::<Vec<_>>(),
+            ["CARGO_ENCODED_RUSTFLAGS", "PBUILD_FEATURES"]
+        );
+        assert_eq!(vars["CARGO_ENCODED_RUSTFLAGS"], "--cfg\x1fa");
+    }
+
+    #[test]
+    fn env_accepts_strings_integers_and_booleans() {
+        let profile = profile("[env]\nA = \"x\"\nB = 3\nC = true\n").unwrap();
+        let env = profile.env.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        assert_eq!(
+            env.collect::<Vec<_>>(),
+            [("A", "x"), ("B", "3"), ("C", "true")]
+        );
+    }
+
+    #[test]
+    fn env_rejects_other_values() {
+        for value in ["[\"x\"]", "{ a = 1 }", "1979-05-27", "1.5"] {
+            let err = profile(&format!("[env]\nA = {}\n", value)).unwrap_err();
+            assert!(
+                matches!(&err, Error::InvalidValue(k, _) if k == "env.A"),
+                "{}: {:?}",
+                value,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use serde_json::json;
+
+use crate::{
+    profile::DEFAULT_SPEC,
+    spec::{FieldSpec, PropSpec, Spec, Type, Value},
+};
+
+/// The JSON Schema draft the generated schemas declare.
+const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+/// Keys of `[profile]` holding arrays of strings.
+const STRING_ARRAYS: [(&str, &str); 6] = [
+    (
+        "tags",
+        "Labels used to select this profile with `tag:<TAG>`.",
+    ),
+    ("bins", "Binaries to build."),
+    ("libs", "Libraries to build."),
+    ("features", "Cargo features to enable."),
+    ("rustflags", "Extra flags passed to rustc."),
+    ("cargo_args", "Extra arguments passed to cargo."),
+];
+
+/// A JSON Schema for profiles using the given spec, for editors to validate
+/// and complete profile files with.
+///
+/// Covers the `[profile]` and `[env]` tables, `[config]` with the fields of
+/// each single type, and a table per type listing its fields and their
+/// properties' types, defaults, allowed values and bounds.
+pub fn schema(name: &str, spec: &Spec) -> serde_json::Value {
+    let mut profile = serde_json::Map::new();
+    profile.insert(
+        "description".into(),
+        json!({ "type": "string", "description": "What this profile is for." }),
+    );
+    profile.insert(
+        "spec".into(),
+        json!({
+            "type": "string",
+            "enum": [name],
+            "description": format!("The spec this profile uses, `{}` if not given.", DEFAULT_SPEC),
+        }),
+    );
+    profile.insert(
+        "target".into(),
+        json!({ "type": "string", "description": "The target triple to build for." }),
+    );
+    for (key, description) in STRING_ARRAYS {
+        profile.insert(
+            key.into(),
+            json!({
+                "type": "array",
+                "items": { "type": "string" },
+                "description": description,
+            }),
+        );
+    }
+
+    // Profiles only need to name the spec they use if it is not the default.
+    let mut required = vec!["description"];
+    if name != DEFAULT_SPEC {
+        required.push("spec");
+    }
+
+    let mut config = serde_json::Map::new();
+    let mut properties = serde_json::Map::new();
+    properties.insert(
+        "profile".into(),
+        json!({
+            "type": "object",
+            "properties": profile,
+            "required": required,
+            "anyOf": [{ "required": ["bins"] }, { "required": ["libs"] }],
+            "additionalProperties": false,
+        }),
+    );
+    properties.insert(
+        "env".into(),
+        json!({
+            "type": "object",
+            "description": "Environment variables set when building.",
+            "additionalProperties": { "type": ["string", "integer", "boolean"] },
+        }),
+    );
+
+    for (index, fields) in spec.fields.iter() {
+        let ty = &spec.types[index];
+        if ty.is_single {
+            config.insert(
+                ty.key.to_string(),
+                json!({
+                    "type": "string",
+                    "enum": fields.keys().map(|x| x.to_string()).collect::<Vec<_>>(),
+                    "description": format!("The `{}` to use.", ty.key),
+                }),
+            );
+        }
+
+        let fields = fields
+            .iter()
+            .map(|(field, field_spec)| (field.to_string(), field_schema(field_spec)))
+            .collect::<serde_json::Map<_, _>>();
+        properties.insert(
+            index.to_string(),
+            json!({
+                "type": "object",
+                "properties": fields,
+                "additionalProperties": false,
+            }),
+        );
+    }
+
+    properties.insert(
+        "config".into(),
+        json!({
+            "type": "object",
+            "description": "The field chosen for each single type.",
+            "properties": config,
+            "additionalProperties": false,
+        }),
+    );
+
+    json!({
+        "$schema": DRAFT,
+        "title": format!("{} profile", spec.name),
+        "description": format!("A cargo-pbuild profile using the `{}` spec.", name),
+        "type": "object",
+        "properties": properties,
+        "required": ["profile", "config"],
+        "additionalProperties": false,
+    })
+}
+
+/// A field is enabled with `true`, or with a table of its properties.
+fn field_schema(field: &FieldSpec) -> serde_json::Value {
+    let properties = field
+        .properties
+        .iter()
+        .map(|(k, v)| (k.to_string(), property_schema(v)))
+        .collect::<serde_json::Map<_, _>>();
+
+    let mut out = json!({
+        "oneOf": [
+            { "type": "boolean" },
+            {
+                "type": "object",
+                "properties": properties,
+                "additionalProperties": false,
+            },
+        ],
+    });
+    let mut description = field.description.clone();
+    if !field.dependencies.deps().is_empty() {
+        if !description.is_empty() {
+            description.push_str("\n\n");
+        }
+        description.push_str(&format!("Requires {}.", *field.dependencies));
+    }
+    if !description.is_empty() {
+        out["description"] = description.into();
+    }
+    out
+}
+
+fn property_schema(prop: &PropSpec) -> serde_json::Value {
+    let mut out = match prop.ty {
+        Type::String => json!({ "type": "string" }),
+        Type::Bool => json!({ "type": "boolean" }),
+        #[cfg(feature = "uuid")]
+        Type::Uuid => json!({ "type": "string", "format": "uuid" }),
+        _ => json!({ "type": "integer" }),
+    };
+
+    if let Some((min, max)) = integer_range(prop.ty) {
+        let bound = |x: Option<&Value>, default: i128| {
+            let x = x.and_then(|x| x.as_integer()).unwrap_or(default);
+            i64::try_from(x)
+                .map(serde_json::Value::from)
+                .or_else(|_| u64::try_from(x).map(serde_json::Value::from))
+                .unwrap_or_default()
+        };
+        out["minimum"] = bound(prop.min.as_ref(), min);
+        out["maximum"] = bound(prop.max.as_ref(), max);
+    }
+    if prop.ty == Type::U64 {
+        // Values above `i64::MAX` can only be written as strings in TOML.
+        out["type"] = json!(["integer", "string"]);
+        out["pattern"] = "^[0-9]+$".into();
+    }
+    if !prop.values.is_empty() {
+        out["enum"] = prop.values.iter().map(serde_json::Value::from).collect();
+    }
+    if let Some(default) = prop.default.as_ref() {
+        out["default"] = default.into();
+    }
+    out
+}
+
+/// The values an integer type can hold.
+fn integer_range(ty: Type) -> Option<(i128, i128)> {
+    Some(match ty {
+        Type::U8 => (0, u8::MAX.into()),
+        Type::U16 => (0, u16::MAX.into()),
+        Type::U32 => (0, u32::MAX.into()),
+        Type::U64 => (0, u64::MAX.into()),
+        Type::I8 => (i8::MIN.into(), i8::MAX.into()),
+        Type::I16 => (i16::MIN.into(), i16::MAX.into()),
+        Type::I32 => (i32::MIN.into(), i32::MAX.into()),
+        Type::I64 => (i64::MIN.into(), i64::MAX.into()),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> serde_json::Value {
+        let spec = Spec::parse_str(
+            "[spec]\nname = \"Boards\"\n\
+             [spec.types]\nboard = { key = \"board\", single = true }\nradio = { key = \"radio\" }\n\
+             [board.dk]\ndescription = \"DK\"\n\
+             [board.custom]\ndescription = \"Custom\"\n\
+             [radio.lora]\ndescription = \"LoRa\"\n\
+             [radio.lora.properties]\n\
+             power = { type = \"u8\", default = 10, min = 1 }\n\
+             offset = { type = \"i16\" }\n\
+             id = { type = \"u64\", max = \"18446744073709551615\" }\n",
+        )
+        .unwrap();
+        super::schema("boards", &spec)
+    }
+
+    #[test]
+    fn profile_needs_bins_or_libs_and_names_its_spec() {
+        let schema = schema();
+        let profile = &schema["properties"]["profile"];
+        assert_eq!(
+            profile["anyOf"],
+            json!([{ "required": ["bins"] }, { "required": ["libs"] }])
+        );
+        assert_eq!(profile["required"], json!(["description", "spec"]));
+        assert_eq!(profile["properties"]["spec"]["enum"], json!(["boards"]));
+        assert_eq!(
+            schema["properties"]["env"]["additionalProperties"]["type"],
+            json!(["string", "integer", "boolean"])
+        );
+    }
+
+    #[test]
+    fn config_lists_the_fields_of_single_types() {
+        let config = &schema()["properties"]["config"];
+        assert_eq!(
+            config["properties"]["board"]["enum"],
+            json!(["dk", "custom"])
+        );
+        assert!(config["properties"].get("radio").is_none());
+        assert_eq!(config["additionalProperties"], json!(false));
+    }
+
+    #[test]
+    fn integer_properties_are_bounded() {
+        let schema = schema();
+        let properties =
+            &schema["properties"]["radio"]["properties"]["lora"]["oneOf"][1]["properties"];
+
+        let power = &properties["power"];
+        assert_eq!(power["type"], "integer");
+        assert_eq!(power["minimum"], 1);
+        assert_eq!(power["maximum"], 255);
+        assert_eq!(power["default"], 10);
+
+        let offset = &properties["offset"];
+        assert_eq!(offset["minimum"], -32768);
+        assert_eq!(offset["maximum"], 32767);
+    }
+
+    #[test]
+    fn u64_properties_also_accept_strings() {
+        let schema = schema();
+        let id =
+            &schema["properties"]["radio"]["properties"]["lora"]["oneOf"][1]["properties"]["id"];
+        assert_eq!(id["type"], json!(["integer", "string"]));
+        assert_eq!(id["pattern"], "^[0-9]+$");
+        assert_eq!(id["minimum"], 0);
+        assert_eq!(id["maximum"], json!(u64::MAX));
+    }
+}
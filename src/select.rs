@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+use crate::{
+    profile::Profile,
+    workspace::{LoadError, Workspace},
+};
+
+/// Picks out profiles by name or by tag. Both forms accept glob patterns:
+/// `eu-*` matches profile names, `tag:release` matches profiles tagged
+/// `release`.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    Name(glob::Pattern),
+    Tag(glob::Pattern),
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tag, pattern) = match s.strip_prefix("tag:") {
+            Some(v) => (true, v),
+            None => (false, s),
+        };
+        let pattern = glob::Pattern::new(pattern)
+            .map_err(|e| format!("invalid profile selector `{}`: {}", s, e))?;
+        Ok(if tag {
+            Selector::Tag(pattern)
+        } else {
+            Selector::Name(pattern)
+        })
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, name: &str, tags: &[String]) -> bool {
+        match self {
+            Selector::Name(p) => p.matches(name),
+            Selector::Tag(p) => tags.iter().any(|x| p.matches(x)),
+        }
+    }
+
+    /// Whether the profile matches any of the selectors, or there are none.
+    pub fn any_matches(selectors: &[Selector], name: &str, tags: &[String]) -> bool {
+        selectors.is_empty() || selectors.iter().any(|x| x.matches(name, tags))
+    }
+}
+
+/// Names of the profiles matching any of the selectors, or all profiles if
+/// there are none, sorted.
+///
+/// Only the tags of each profile are read, and only when no selector matches
+/// its name, so a profile that fails to load can still be selected by name
+/// and reported by the caller. Tags that cannot be read are an error.
+pub fn select_profiles(
+    workspace: &Workspace,
+    selectors: &[Selector],
+) -> Result<Vec<String>, LoadError> {
+    let needs_tags = selectors.iter().any(|x| matches!(x, Selector::Tag(_)));
+
+    let mut out = vec![];
+    for name in workspace.profile_names()? {
+        let tags = if needs_tags && !Selector::any_matches(selectors, &name, &[]) {
+            let raw = std::fs::read_to_string(workspace.profile_path(&name))?;
+            Profile::tags(&raw).map_err(|e| LoadError::Profile(name.clone(), e))?
+        } else {
+            vec![]
+        };
+        if Selector::any_matches(selectors, &name, &tags) {
+            out.push(name);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A workspace with one profile per `(name, tags)` pair, in a fresh
+    /// directory under the system temp dir.
+    fn workspace(name: &str, profiles: &[(&str, &str)]) -> Workspace {
+        let root =
+            std::env::temp_dir().join(format!("pbuild-select-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let workspace = Workspace {
+            profiles_dir: root.join("profiles"),
+            specs_dir: root.join("profiles").join("specs"),
+            dist_dir: root.join("dist"),
+            target_dir: root.join("target"),
+            artifact_name: Default::default(),
+            default_profile: None,
+            lints: Default::default(),
+            root,
+        };
+        std::fs::create_dir_all(&workspace.profiles_dir).unwrap();
+        for (name, tags) in profiles {
+            let contents = format!(
+                "[profile]\ndescription = \"{}\"\nbins = [\"app\"]\ntags = {}\n[config]\n",
+                name, tags
+            );
+            std::fs::write(workspace.profile_path(name), contents).unwrap();
+        }
+        workspace
+    }
+
+    fn select(workspace: &Workspace, selectors: &[&str]) -> Vec<String> {
+        let selectors = selectors
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect::<Vec<Selector>>();
+        select_profiles(workspace, &selectors).unwrap()
+    }
+
+    #[test]
+    fn parses_names_tags_and_globs() {
+        match "eu-*".parse::<Selector>().unwrap() {
+            Selector::Name(p) => assert_eq!(p.as_str(), "eu-*"),
+            other => panic!("expected a name selector, got {:?}", other),
+        }
+        match "tag:rel*".parse::<Selector>().unwrap() {
+            Selector::Tag(p) => assert_eq!(p.as_str(), "rel*"),
+            other => panic!("expected a tag selector, got {:?}", other),
+        }
+
+        let tags = ["release".to_string()];
+        assert!("tag:rel*"
+            .parse::<Selector>()
+            .unwrap()
+            .matches("dev", &tags));
+        assert!(!"rel*".parse::<Selector>().unwrap().matches("dev", &tags));
+        assert!("d?v".parse::<Selector>().unwrap().matches("dev", &[]));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for s in ["eu-[", "tag:**x"] {
+            let err = s.parse::<Selector>().unwrap_err();
+            assert!(
+                err.starts_with(&format!("invalid profile selector `{}`: ", s)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn selects_by_name_glob_and_tag() {
+        let workspace = workspace(
+            "match",
+            &[
+                ("dev", "[]"),
+                ("eu-dk", "[\"release\"]"),
+                ("eu-custom", "[]"),
+                ("us-dk", "[\"release\", \"us\"]"),
+            ],
+        );
+
+        assert_eq!(
+            select(&workspace, &[]),
+            ["dev", "eu-custom", "eu-dk", "us-dk"]
+        );
+        assert_eq!(select(&workspace, &["dev"]), ["dev"]);
+        assert_eq!(select(&workspace, &["eu-*"]), ["eu-custom", "eu-dk"]);
+        assert_eq!(select(&workspace, &["tag:release"]), ["eu-dk", "us-dk"]);
+        assert_eq!(select(&workspace, &["dev", "tag:u*"]), ["dev", "us-dk"]);
+
+        let _ = std::fs::remove_dir_all(&workspace.root);
+    }
+
+    #[test]
+    fn unreadable_tags_are_an_error() {
+        let workspace = workspace("bad-tags", &[("bad", "\"release\""), ("dev", "[]")]);
+
+        let selectors = ["tag:release".parse().unwrap()];
+        match select_profiles(&workspace, &selectors) {
+            Err(LoadError::Profile(name, _)) => assert_eq!(name, "bad"),
+            other => panic!("expected a profile error, got {:?}", other),
+        }
+
+        // Matching by name does not need the tags.
+        assert_eq!(select(&workspace, &["bad", "tag:release"]), ["bad"]);
+
+        let _ = std::fs::remove_dir_all(&workspace.root);
+    }
+}
@@ -0,0 +1,93 @@
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+
+use crate::cfg::is_identifier;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("`{0}` is not a valid environment variable name.")]
+    InvalidName(String),
+}
+
+/// Syntaxes environment variables can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Sh,
+    Fish,
+    PowerShell,
+    Dotenv,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" | "bash" | "zsh" => Ok(Shell::Sh),
+            "fish" => Ok(Shell::Fish),
+            "powershell" | "pwsh" => Ok(Shell::PowerShell),
+            "dotenv" => Ok(Shell::Dotenv),
+            _ => Err(format!(
+                "unknown shell `{}`, expected `sh`, `fish`, `powershell` or `dotenv`",
+                s
+            )),
+        }
+    }
+}
+
+impl Shell {
+    /// Renders one statement per variable, each setting and exporting it.
+    ///
+    /// Names are written unquoted, so any name other than letters, digits
+    /// and `_`, not starting with a digit, is rejected.
+    pub fn render(&self, vars: &IndexMap<String, String>) -> Result<String, Error> {
+        let mut out = String::new();
+        for (k, v) in vars.iter() {
+            if !is_identifier(k) {
+                return Err(Error::InvalidName(k.to_string()));
+            }
+            let line = match self {
+                Shell::Sh => format!("export {}='{}'", k, v.replace('\'', "'\\''")),
+                Shell::Fish => format!(
+                    "set -gx {} '{}'",
+                    k,
+                    v.replace('\\', "\\\\").replace('\'', "\\'")
+                ),
+                Shell::PowerShell => format!("$env:{} = '{}'", k, v.replace('\'', "''")),
+                Shell::Dotenv => format!(
+                    "{}=\"{}\"",
+                    k,
+                    v.replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n")
+                ),
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_values() {
+        let mut vars = IndexMap::new();
+        vars.insert("A_1".to_string(), "it's".to_string());
+        assert_eq!(Shell::Sh.render(&vars).unwrap(), "export A_1='it'\\''s'\n");
+        assert_eq!(Shell::Fish.render(&vars).unwrap(), "set -gx A_1 'it\\'s'\n");
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for name in ["X=1; rm -rf ~;Y", "1A", "A-B", ""] {
+            let mut vars = IndexMap::new();
+            vars.insert(name.to_string(), "1".to_string());
+            assert!(matches!(Shell::Sh.render(&vars), Err(Error::InvalidName(x)) if x == name));
+        }
+    }
+}
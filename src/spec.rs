@@ -1,6 +1,10 @@
 use indexmap::IndexMap;
 use nova::newtype;
-use std::{fmt::Display, ops::Deref, path::Path};
+use std::{
+    fmt::Display,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Clone)]
 pub enum DependencyOp {
@@ -11,38 +15,101 @@ pub enum DependencyOp {
 
 #[derive(Debug, Clone)]
 pub struct Dep {
-    ty: TypeKey,
-    name: String,
+    pub ty: TypeKey,
+    pub name: String,
 }
 
 impl Dep {
-    fn parse(input: &str, types: &IndexMap<TypeIndex, TypeSpec>) -> Result<Dep, ()> {
-        let x = input.split(":").collect::<Vec<_>>();
-        if x.len() != 2 {
-            // TODO
-            return Err(());
+    fn parse(input: &str, types: &IndexMap<TypeIndex, TypeSpec>) -> Option<Dep> {
+        let (ty, name) = input.split_once(':')?;
+        let ty = TypeKey(ty.trim().to_string());
+        if !types.values().any(|x| x.key == ty) {
+            return None;
+        }
+
+        Some(Dep {
+            ty,
+            name: name.trim().to_string(),
+        })
+    }
+}
+
+impl Display for Dep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.ty, self.name))
+    }
+}
+
+impl DependencyOp {
+    /// Evaluates the dependency tree, using `is_enabled` to check whether a
+    /// single dependency is enabled.
+    pub fn is_satisfied<F: Fn(&Dep) -> bool + Copy>(&self, is_enabled: F) -> bool {
+        match self {
+            DependencyOp::Or(deps) => deps.iter().any(is_enabled),
+            DependencyOp::And(ops) => ops.iter().all(|x| x.is_satisfied(is_enabled)),
+            DependencyOp::Dep(dep) => is_enabled(dep),
         }
+    }
 
-        let ty = TypeKey(x[0].to_string());
-        if types.values().find(|x| x.key == ty).is_none() {
-            // TODO
-            return Err(());
+    /// Every dependency mentioned anywhere in the tree.
+    pub fn deps(&self) -> Vec<&Dep> {
+        match self {
+            DependencyOp::Or(deps) => deps.iter().collect(),
+            DependencyOp::And(ops) => ops.iter().flat_map(|x| x.deps()).collect(),
+            DependencyOp::Dep(dep) => vec![dep],
         }
+    }
 
-        let name = x[1].to_string();
+    /// Dependencies that must all be enabled for the tree to be satisfied.
+    pub fn required_deps(&self) -> Vec<&Dep> {
+        match self {
+            DependencyOp::Or(deps) if deps.len() == 1 => vec![&deps[0]],
+            DependencyOp::Or(_) => vec![],
+            DependencyOp::And(ops) => ops.iter().flat_map(|x| x.required_deps()).collect(),
+            DependencyOp::Dep(dep) => vec![dep],
+        }
+    }
+}
 
-        Ok(Dep { ty, name })
+impl Display for DependencyOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyOp::Or(deps) => {
+                let deps = deps.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                if deps.len() > 1 {
+                    f.write_fmt(format_args!("({})", deps.join(" OR ")))
+                } else {
+                    f.write_str(&deps.join(""))
+                }
+            }
+            DependencyOp::And(ops) => {
+                let ops = ops.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                f.write_str(&ops.join(" AND "))
+            }
+            DependencyOp::Dep(dep) => Display::fmt(dep, f),
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Dependencies(DependencyOp);
 
+impl Deref for Dependencies {
+    type Target = DependencyOp;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 impl Dependencies {
     fn parse(
+        section: &str,
         types: &IndexMap<TypeIndex, TypeSpec>,
-        raw: &Vec<&str>,
+        raw: &[&str],
     ) -> Result<Dependencies, FieldsError> {
+        let invalid = |x: &str| FieldsError::InvalidDependency(section.to_string(), x.to_string());
+
         let op = raw
             .iter()
             .map(|x| {
@@ -50,15 +117,16 @@ impl Dependencies {
                     let ors = x
                         .split("OR")
                         .map(|x| x.trim())
-                        .map(|x| Dep::parse(x, types))
-                        .collect::<Result<Vec<_>, _>>()
-                        .unwrap();
-                    DependencyOp::Or(ors)
+                        .map(|x| Dep::parse(x, types).ok_or_else(|| invalid(x)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(DependencyOp::Or(ors))
                 } else {
-                    DependencyOp::Dep(Dep::parse(x, types).unwrap())
+                    Ok(DependencyOp::Dep(
+                        Dep::parse(x, types).ok_or_else(|| invalid(x))?,
+                    ))
                 }
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(Dependencies(DependencyOp::And(op)))
     }
@@ -118,7 +186,21 @@ pub enum Type {
 }
 
 impl Type {
-    fn as_str(&self) -> &'static str {
+    pub fn is_integer(&self) -> bool {
+        !matches!(self, Type::String | Type::Bool) && !self.is_uuid()
+    }
+
+    #[cfg(feature = "uuid")]
+    fn is_uuid(&self) -> bool {
+        matches!(self, Type::Uuid)
+    }
+
+    #[cfg(not(feature = "uuid"))]
+    fn is_uuid(&self) -> bool {
+        false
+    }
+
+    pub fn as_str(&self) -> &'static str {
         match self {
             Type::String => "string",
             Type::Bool => "bool",
@@ -182,12 +264,47 @@ impl Display for Value {
             Value::I16(x) => Display::fmt(x, f),
             Value::I32(x) => Display::fmt(x, f),
             Value::I64(x) => Display::fmt(x, f),
+            #[cfg(feature = "uuid")]
             Value::Uuid(x) => Display::fmt(x, f),
         }
     }
 }
 
+impl From<&Value> for serde_json::Value {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::String(x) => x.as_str().into(),
+            Value::Bool(x) => (*x).into(),
+            Value::U8(x) => (*x).into(),
+            Value::U16(x) => (*x).into(),
+            Value::U32(x) => (*x).into(),
+            Value::U64(x) => (*x).into(),
+            Value::I8(x) => (*x).into(),
+            Value::I16(x) => (*x).into(),
+            Value::I32(x) => (*x).into(),
+            Value::I64(x) => (*x).into(),
+            #[cfg(feature = "uuid")]
+            Value::Uuid(x) => x.to_hyphenated_ref().to_string().into(),
+        }
+    }
+}
+
 impl Value {
+    /// The value as an integer, if it is one.
+    pub fn as_integer(&self) -> Option<i128> {
+        Some(match self {
+            Value::U8(x) => *x as i128,
+            Value::U16(x) => *x as i128,
+            Value::U32(x) => *x as i128,
+            Value::U64(x) => *x as i128,
+            Value::I8(x) => *x as i128,
+            Value::I16(x) => *x as i128,
+            Value::I32(x) => *x as i128,
+            Value::I64(x) => *x as i128,
+            _ => return None,
+        })
+    }
+
     pub fn default(ty: Type) -> Value {
         match ty {
             Type::String => Value::String(Default::default()),
@@ -221,10 +338,13 @@ impl Value {
                 .as_integer()
                 .and_then(|x| x.try_into().ok())
                 .map(Self::U32),
-            Type::U64 => val
-                .as_integer()
-                .and_then(|x| x.try_into().ok())
-                .map(Self::U64),
+            // TOML integers are signed, so larger values are written as
+            // strings.
+            Type::U64 => match val {
+                toml::Value::String(x) => x.parse().ok(),
+                _ => val.as_integer().and_then(|x| x.try_into().ok()),
+            }
+            .map(Self::U64),
             Type::I8 => val
                 .as_integer()
                 .and_then(|x| x.try_into().ok())
@@ -237,10 +357,7 @@ impl Value {
                 .as_integer()
                 .and_then(|x| x.try_into().ok())
                 .map(Self::I32),
-            Type::I64 => val
-                .as_integer()
-                .and_then(|x| x.try_into().ok())
-                .map(Self::I64),
+            Type::I64 => val.as_integer().map(Self::I64),
             #[cfg(feature = "uuid")]
             Type::Uuid => val
                 .as_str()
@@ -254,6 +371,9 @@ impl Value {
 pub struct PropSpec {
     pub ty: Type,
     pub default: Option<Value>,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    pub values: Vec<Value>,
 }
 
 impl PropSpec {
@@ -280,7 +400,76 @@ impl PropSpec {
             None => None,
         };
 
-        Ok(PropSpec { ty, default })
+        let parse_bound = |key: &'static str| match raw.get(key) {
+            Some(_) if !ty.is_integer() => Err(FieldsError::UnsupportedConstraint {
+                field: name.to_string(),
+                key,
+                ty: ty.as_str(),
+            }),
+            Some(v) => Ok(Some(Value::new(ty, v).ok_or_else(|| {
+                FieldsError::InvalidFieldType {
+                    field: name.to_string(),
+                    key,
+                    ty: ty.as_str(),
+                }
+            })?)),
+            None => Ok(None),
+        };
+
+        let min = parse_bound("min")?;
+        let max = parse_bound("max")?;
+
+        let values = match raw.get("values") {
+            Some(v) => v
+                .as_array()
+                .and_then(|x| {
+                    x.iter()
+                        .map(|x| Value::new(ty, x))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| FieldsError::InvalidFieldType {
+                    field: name.to_string(),
+                    key: "values",
+                    ty: "array",
+                })?,
+            None => vec![],
+        };
+
+        Ok(PropSpec {
+            ty,
+            default,
+            min,
+            max,
+            values,
+        })
+    }
+
+    /// Checks a value against the property's `min`, `max` and `values`
+    /// constraints, describing the first one violated.
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        if let (Some(min), Some(v)) = (self.min.as_ref(), value.as_integer()) {
+            if v < min.as_integer().unwrap_or(i128::MIN) {
+                return Err(format!("{} is less than the minimum of {}", value, min));
+            }
+        }
+
+        if let (Some(max), Some(v)) = (self.max.as_ref(), value.as_integer()) {
+            if v > max.as_integer().unwrap_or(i128::MAX) {
+                return Err(format!("{} is greater than the maximum of {}", value, max));
+            }
+        }
+
+        if !self.values.is_empty() && !self.values.contains(value) {
+            let values = self
+                .values
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(format!("{} is not one of: {}", value, values));
+        }
+
+        Ok(())
     }
 }
 
@@ -300,10 +489,78 @@ pub type TypeIndex = String;
 #[newtype(new, display)]
 pub type FieldKey = String;
 
+/// How cfg names are derived from type, field and property names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfgStyle {
+    /// `type_field` and `type_field_property`, converted to snake case.
+    Snake,
+    /// As [`CfgStyle::Snake`], but without any case conversion.
+    AsIs,
+    /// As [`CfgStyle::Snake`], but multi types emit `type="field"` for each
+    /// enabled field rather than a `type_field` flag, the same way Cargo
+    /// passes features. Can be set per type with `cfg = "key-value"` in
+    /// `[spec.types]`.
+    KeyValue,
+}
+
+impl CfgStyle {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CfgStyle::Snake => "snake",
+            CfgStyle::AsIs => "as-is",
+            CfgStyle::KeyValue => "key-value",
+        }
+    }
+
+    fn parse(input: &str) -> Option<CfgStyle> {
+        Some(match input {
+            "snake" => Self::Snake,
+            "as-is" => Self::AsIs,
+            "key-value" => Self::KeyValue,
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TypeSpec {
     pub key: TypeKey,
     pub is_single: bool,
+    pub cfg_prefix: String,
+    pub cfg_style: CfgStyle,
+}
+
+impl TypeSpec {
+    fn cfg_ident(&self, name: String) -> String {
+        use heck::SnakeCase;
+        match self.cfg_style {
+            CfgStyle::AsIs => format!("{}{}", self.cfg_prefix, name),
+            CfgStyle::Snake | CfgStyle::KeyValue => {
+                format!("{}{}", self.cfg_prefix, name.to_snake_case())
+            }
+        }
+    }
+
+    /// Whether enabled fields are emitted as `type="field"` rather than as
+    /// one flag per field.
+    pub fn is_key_value(&self) -> bool {
+        self.is_single || self.cfg_style == CfgStyle::KeyValue
+    }
+
+    /// The cfg name carrying the enabled field(s) of a key/value type.
+    pub fn cfg_name(&self) -> String {
+        self.cfg_ident(self.key.to_string())
+    }
+
+    /// The cfg name set when a field of a multi type is enabled.
+    pub fn field_cfg_name(&self, field: &str) -> String {
+        self.cfg_ident(format!("{}_{}", self.key, field))
+    }
+
+    /// The cfg name carrying the value of a field's property.
+    pub fn property_cfg_name(&self, field: &str, property: &str) -> String {
+        self.cfg_ident(format!("{}_{}_{}", self.key, field, property))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -313,6 +570,115 @@ pub struct Spec {
     pub fields: IndexMap<TypeIndex, IndexMap<FieldKey, FieldSpec>>,
 }
 
+/// A single spec file on its own: the types and fields it declares, and
+/// every file it includes, directly or not.
+#[derive(Debug, Clone)]
+struct Unit {
+    name: String,
+    types: IndexMap<TypeIndex, TypeSpec>,
+    fields: IndexMap<TypeIndex, IndexMap<FieldKey, FieldSpec>>,
+    /// Canonical paths of included files, each before the files including
+    /// it.
+    includes: Vec<PathBuf>,
+}
+
+/// The `cfg_prefix` and `cfg_style` of a spec, which apply to every file it
+/// includes.
+#[derive(Debug, Clone)]
+struct CfgSettings {
+    prefix: String,
+    style: CfgStyle,
+}
+
+/// Resolves `[spec] include`, parsing each file once however many specs
+/// include it.
+#[derive(Debug, Default)]
+struct Loader {
+    units: IndexMap<PathBuf, Unit>,
+    /// The chain of files currently being loaded, to detect cycles.
+    stack: Vec<PathBuf>,
+    /// The settings of the spec being loaded, set by the first file parsed.
+    cfg: Option<CfgSettings>,
+}
+
+impl Loader {
+    /// Loads a file and everything it includes, returning its canonical
+    /// path.
+    fn load(&mut self, path: &Path) -> Result<PathBuf, Error> {
+        let canonical = path.canonicalize()?;
+        if self.stack.contains(&canonical) {
+            let chain = self
+                .stack
+                .iter()
+                .skip_while(|x| **x != canonical)
+                .chain(std::iter::once(&canonical))
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(Error::Cycle(chain.join(" -> ")));
+        }
+        if self.units.contains_key(&canonical) {
+            return Ok(canonical);
+        }
+
+        self.stack.push(canonical.clone());
+        let unit = std::fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|s| self.parse_unit(&s, path.parent().unwrap_or_else(|| Path::new("."))));
+        self.stack.pop();
+
+        self.units.insert(canonical.clone(), unit?);
+        Ok(canonical)
+    }
+
+    fn parse_unit(&mut self, s: &str, base: &Path) -> Result<Unit, Error> {
+        let raw: toml::map::Map<String, toml::Value> = toml::from_str(s)?;
+        let (name, cfg, own_types) = Spec::parse_spec(&raw, self.cfg.as_ref())?;
+        self.cfg.get_or_insert(cfg);
+
+        let mut includes = vec![];
+        for include in Spec::parse_includes(&raw)? {
+            let path = base.join(&include);
+            let canonical = self
+                .load(&path)
+                .map_err(|e| Error::Include(path.clone(), Box::new(e)))?;
+            for x in self.units[&canonical].includes.iter().chain([&canonical]) {
+                if !includes.contains(x) {
+                    includes.push(x.clone());
+                }
+            }
+        }
+
+        let mut types = IndexMap::new();
+        for path in includes.iter() {
+            Spec::merge_types(&mut types, self.units[path].types.clone())?;
+        }
+        Spec::merge_types(&mut types, own_types.clone())?;
+        let fields = Spec::parse_fields(&raw, &own_types, &types)?;
+
+        Ok(Unit {
+            name,
+            types: own_types,
+            fields,
+            includes,
+        })
+    }
+
+    /// Merges a unit with everything it includes into a spec.
+    fn assemble(&self, unit: &Unit) -> Result<Spec, Error> {
+        let mut types = IndexMap::new();
+        let mut fields = IndexMap::new();
+        for included in unit.includes.iter().map(|x| &self.units[x]).chain([unit]) {
+            Spec::merge_types(&mut types, included.types.clone())?;
+            Spec::merge_fields(&mut fields, included.fields.clone())?;
+        }
+        Ok(Spec {
+            name: unit.name.clone(),
+            types,
+            fields,
+        })
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Could not load file.")]
@@ -326,6 +692,28 @@ pub enum Error {
 
     #[error("Error parsing type table.")]
     Fields(#[from] FieldsError),
+
+    #[error("Error loading included spec `{0}`.")]
+    Include(PathBuf, #[source] Box<Error>),
+
+    #[error("Specs include each other in a cycle: {0}.")]
+    Cycle(String),
+
+    #[error("Type `{0}` is defined by more than one spec.")]
+    DuplicateType(TypeIndex),
+
+    #[error("Field `{0}.{1}` is defined by more than one spec.")]
+    DuplicateField(TypeIndex, FieldKey),
+
+    #[error("cfg `{name}` is produced by both {first} and {second}.")]
+    CfgCollision {
+        name: String,
+        first: String,
+        second: String,
+    },
+
+    #[error("cfg `{name}` produced by {origin} is not a valid identifier.")]
+    InvalidCfgName { name: String, origin: String },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -341,6 +729,15 @@ pub enum SpecError {
 
     #[error("Value for key `{0}` in [spec.types] is not of type `string`.")]
     InvalidTypeValue(String),
+
+    #[error("`{1}` field of `{0}` in [spec.types] is not of type `{2}`.")]
+    InvalidTypeField(String, &'static str, &'static str),
+
+    #[error("`cfg_prefix` in [spec] must start with a letter or `_` and contain only letters, digits and `_`, found `{0}`.")]
+    InvalidCfgPrefix(String),
+
+    #[error("`{0}` in [spec] is `{1}`, but the spec including it uses `{2}`.")]
+    InheritedMismatch(&'static str, String, String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -363,6 +760,16 @@ pub enum FieldsError {
 
     #[error("[{0}] section not found or wrong type.")]
     SectionMissing(String),
+
+    #[error("`{key}` field in [{field}] is not supported for properties of type `{ty}`.")]
+    UnsupportedConstraint {
+        field: String,
+        key: &'static str,
+        ty: &'static str,
+    },
+
+    #[error("Dependency `{1}` in [{0}] is not of the form `type:field` with a known type.")]
+    InvalidDependency(String, String),
 }
 
 impl FieldSpec {
@@ -396,7 +803,7 @@ impl FieldSpec {
         };
 
         let dependencies = match raw_dependencies {
-            Some(v) => Dependencies::parse(types, &v)?,
+            Some(v) => Dependencies::parse(&section, types, &v)?,
             None => Dependencies::empty(),
         };
 
@@ -423,26 +830,196 @@ impl FieldSpec {
 }
 
 impl Spec {
+    /// Every cfg name a profile using this spec could produce, paired with a
+    /// description of where it comes from.
+    pub fn cfg_names(&self) -> Vec<(String, String)> {
+        let mut out = vec![];
+
+        for (index, fields) in self.fields.iter() {
+            let ty = &self.types[index];
+            if ty.is_key_value() {
+                out.push((ty.cfg_name(), format!("type `{}`", ty.key)));
+            }
+
+            for (field, field_spec) in fields.iter() {
+                if !ty.is_key_value() {
+                    out.push((
+                        ty.field_cfg_name(field),
+                        format!("field `{}.{}`", ty.key, field),
+                    ));
+                }
+
+                for prop in field_spec.properties.keys() {
+                    out.push((
+                        ty.property_cfg_name(field, prop),
+                        format!("property `{}.{}.{}`", ty.key, field, prop),
+                    ));
+                }
+            }
+        }
+
+        out
+    }
+
+    /// `--check-cfg` arguments declaring every cfg a profile using this spec
+    /// could produce, so rustc's `unexpected_cfgs` lint accepts them.
+    pub fn check_cfg_flags(&self) -> Vec<String> {
+        let mut out = vec![];
+
+        for (index, fields) in self.fields.iter() {
+            let ty = &self.types[index];
+            let mut cfgs = vec![];
+
+            if ty.is_key_value() {
+                let values = fields
+                    .keys()
+                    .map(|x| format!("{:?}", x.as_str()))
+                    .collect::<Vec<_>>();
+                cfgs.push(format!(
+                    "cfg({},values({}))",
+                    ty.cfg_name(),
+                    values.join(",")
+                ));
+            }
+
+            for (field, field_spec) in fields.iter() {
+                if !ty.is_key_value() {
+                    cfgs.push(format!("cfg({})", ty.field_cfg_name(field)));
+                }
+
+                for (prop, prop_spec) in field_spec.properties.iter() {
+                    let name = ty.property_cfg_name(field, prop);
+                    cfgs.push(match prop_spec.ty {
+                        Type::Bool => format!("cfg({})", name),
+                        _ => format!("cfg({},values(any()))", name),
+                    });
+                }
+            }
+
+            for cfg in cfgs {
+                out.push("--check-cfg".to_string());
+                out.push(cfg);
+            }
+        }
+
+        out
+    }
+
+    /// Looks up a field by its type key.
+    pub fn field(&self, ty: &TypeKey, field: &str) -> Option<&FieldSpec> {
+        let (index, _) = self.types.iter().find(|(_, x)| &x.key == ty)?;
+        self.fields
+            .get(index)?
+            .iter()
+            .find(|(k, _)| k.as_str() == field)
+            .map(|(_, v)| v)
+    }
+
+    /// Looks up a type by its index or its key.
+    pub fn find_type(&self, name: &str) -> Option<(&TypeIndex, &TypeSpec)> {
+        self.types
+            .iter()
+            .find(|(index, ty)| index.as_str() == name || ty.key.as_str() == name)
+    }
+
+    /// Parses a spec file. Paths in `[spec] include` are resolved relative
+    /// to the directory containing the file.
     #[inline]
     pub fn parse_path<P: AsRef<Path>>(path: P) -> Result<Spec, Error> {
-        Self::parse_str(&std::fs::read_to_string(path)?)
+        let mut loader = Loader::default();
+        let path = loader.load(path.as_ref())?;
+        let spec = loader.assemble(&loader.units[&path])?;
+        spec.check_cfg_names()?;
+        Ok(spec)
     }
 
+    /// Parses a spec from a string. Paths in `[spec] include` are resolved
+    /// relative to the current directory.
     #[inline]
     pub fn parse_str(s: &str) -> Result<Spec, Error> {
-        let raw: toml::map::Map<String, toml::Value> = toml::from_str(s)?;
-        let (name, types) = Self::parse_spec(&raw)?;
-        let fields = Self::parse_fields(&raw, &types)?;
+        let mut loader = Loader::default();
+        let unit = loader.parse_unit(s, Path::new("."))?;
+        let spec = loader.assemble(&unit)?;
+        spec.check_cfg_names()?;
+        Ok(spec)
+    }
 
-        Ok(Spec {
-            name,
-            types,
-            fields,
-        })
+    /// Rejects specs where a field or property would produce a cfg name
+    /// rustc does not accept, or where two different fields or properties
+    /// would produce the same cfg name, which would make the generated cfg
+    /// flags ambiguous.
+    fn check_cfg_names(&self) -> Result<(), Error> {
+        let mut seen: IndexMap<String, String> = IndexMap::new();
+        for (name, origin) in self.cfg_names() {
+            if !crate::cfg::is_identifier(&name) {
+                return Err(Error::InvalidCfgName { name, origin });
+            }
+            match seen.get(&name) {
+                Some(first) if first != &origin => {
+                    return Err(Error::CfgCollision {
+                        name,
+                        first: first.clone(),
+                        second: origin,
+                    })
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(name, origin);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_includes(raw: &toml::map::Map<String, toml::Value>) -> Result<Vec<String>, SpecError> {
+        match raw.get("spec").and_then(|x| x.get("include")) {
+            Some(v) => v
+                .as_array()
+                .and_then(|x| {
+                    x.iter()
+                        .map(|x| x.as_str().map(|x| x.to_string()))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or(SpecError::InvalidFieldType("include", "array<string>")),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn merge_types(
+        types: &mut IndexMap<TypeIndex, TypeSpec>,
+        other: IndexMap<TypeIndex, TypeSpec>,
+    ) -> Result<(), Error> {
+        for (k, v) in other {
+            if types.contains_key(&k) {
+                return Err(Error::DuplicateType(k));
+            }
+            types.insert(k, v);
+        }
+        Ok(())
+    }
+
+    fn merge_fields(
+        fields: &mut IndexMap<TypeIndex, IndexMap<FieldKey, FieldSpec>>,
+        other: IndexMap<TypeIndex, IndexMap<FieldKey, FieldSpec>>,
+    ) -> Result<(), Error> {
+        for (ty, other_fields) in other {
+            let entry = fields.entry(ty.clone()).or_default();
+            for (k, v) in other_fields {
+                if entry.contains_key(&k) {
+                    return Err(Error::DuplicateField(ty, k));
+                }
+                entry.insert(k, v);
+            }
+        }
+        Ok(())
     }
 
+    /// Parses the type sections of a single spec file. Every type declared in
+    /// `own_types` must have a section; types from included specs may have one
+    /// to add further fields.
     fn parse_fields(
         raw: &toml::map::Map<String, toml::Value>,
+        own_types: &IndexMap<TypeIndex, TypeSpec>,
         types: &IndexMap<TypeIndex, TypeSpec>,
     ) -> Result<IndexMap<TypeIndex, IndexMap<FieldKey, FieldSpec>>, FieldsError> {
         let undefined_types = raw
@@ -456,7 +1033,7 @@ impl Spec {
             return Err(FieldsError::ExcessKeys(undefined_types));
         }
 
-        let missing_keys = types
+        let missing_keys = own_types
             .keys()
             .filter(|x| &***x != "spec" && !raw.contains_key(&***x))
             .cloned()
@@ -474,6 +1051,10 @@ impl Spec {
         let mut out_types = IndexMap::new();
 
         for k in types.keys() {
+            if !own_types.contains_key(k) && !raw.contains_key(&k.0) {
+                continue;
+            }
+
             let section = raw
                 .get(&k.0)
                 .and_then(|x| x.as_table())
@@ -496,26 +1077,66 @@ impl Spec {
         Ok(out_types)
     }
 
+    /// Parses the `[spec]` table. The settings of the including spec, if
+    /// any, are used in place of `cfg_prefix` and `cfg_style`, which must
+    /// either be left out or match them.
     fn parse_spec(
         raw: &toml::map::Map<String, toml::Value>,
-    ) -> Result<(String, IndexMap<TypeIndex, TypeSpec>), SpecError> {
+        inherited: Option<&CfgSettings>,
+    ) -> Result<(String, CfgSettings, IndexMap<TypeIndex, TypeSpec>), SpecError> {
         let raw_spec = raw
             .get("spec")
             .and_then(|x| x.as_table())
-            .ok_or_else(|| SpecError::SpecMissing)?;
+            .ok_or(SpecError::SpecMissing)?;
 
         let name = raw_spec
             .get("name")
-            .ok_or_else(|| SpecError::MissingField("name"))?
+            .ok_or(SpecError::MissingField("name"))?
             .as_str()
-            .ok_or_else(|| SpecError::InvalidFieldType("name", "string"))?
+            .ok_or(SpecError::InvalidFieldType("name", "string"))?
             .to_string();
 
+        let cfg_prefix = match raw_spec.get("cfg_prefix") {
+            Some(v) => v
+                .as_str()
+                .ok_or(SpecError::InvalidFieldType("cfg_prefix", "string"))?
+                .to_string(),
+            None => inherited.map(|x| x.prefix.clone()).unwrap_or_default(),
+        };
+        if !cfg_prefix.is_empty() && !crate::cfg::is_identifier(&cfg_prefix) {
+            return Err(SpecError::InvalidCfgPrefix(cfg_prefix));
+        }
+        if let Some(x) = inherited.filter(|x| x.prefix != cfg_prefix) {
+            return Err(SpecError::InheritedMismatch(
+                "cfg_prefix",
+                cfg_prefix,
+                x.prefix.clone(),
+            ));
+        }
+
+        let cfg_style = match raw_spec.get("cfg_style") {
+            Some(v) => v
+                .as_str()
+                .and_then(CfgStyle::parse)
+                .ok_or(SpecError::InvalidFieldType(
+                    "cfg_style",
+                    "\"snake\", \"as-is\" or \"key-value\"",
+                ))?,
+            None => inherited.map(|x| x.style).unwrap_or(CfgStyle::Snake),
+        };
+        if let Some(x) = inherited.filter(|x| x.style != cfg_style) {
+            return Err(SpecError::InheritedMismatch(
+                "cfg_style",
+                cfg_style.as_str().to_string(),
+                x.style.as_str().to_string(),
+            ));
+        }
+
         let types = raw_spec
             .get("types")
-            .ok_or_else(|| SpecError::MissingField("types"))?
+            .ok_or(SpecError::MissingField("types"))?
             .as_table()
-            .ok_or_else(|| SpecError::InvalidFieldType("types", "map<string, string>"))?
+            .ok_or(SpecError::InvalidFieldType("types", "map<string, string>"))?
             .iter()
             .map(|(k, v)| {
                 let k = k.to_string();
@@ -523,10 +1144,23 @@ impl Spec {
                     toml::Value::String(s) => Ok(TypeSpec {
                         key: TypeKey(s.to_string()),
                         is_single: false,
+                        cfg_prefix: cfg_prefix.clone(),
+                        cfg_style,
                     }),
                     toml::Value::Table(t) => Ok(TypeSpec {
                         key: TypeKey(t.get("key").and_then(|x| x.as_str()).unwrap().to_string()),
                         is_single: t.get("single").is_some(),
+                        cfg_prefix: cfg_prefix.clone(),
+                        cfg_style: match t.get("cfg") {
+                            Some(v) => v.as_str().and_then(CfgStyle::parse).ok_or_else(|| {
+                                SpecError::InvalidTypeField(
+                                    k.clone(),
+                                    "cfg",
+                                    "\"snake\", \"as-is\" or \"key-value\"",
+                                )
+                            })?,
+                            None => cfg_style,
+                        },
                     }),
                     _ => Err(SpecError::InvalidTypeValue(k.clone())),
                 }?;
@@ -534,7 +1168,11 @@ impl Spec {
             })
             .collect::<Result<IndexMap<_, _>, SpecError>>()?;
 
-        Ok((name, types))
+        let cfg = CfgSettings {
+            prefix: cfg_prefix,
+            style: cfg_style,
+        };
+        Ok((name, cfg, types))
     }
 }
 
@@ -566,3 +1204,226 @@ impl Display for Spec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes spec files into a fresh directory under the system temp dir.
+    fn write_specs(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pbuild-spec-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (path, contents) in files {
+            std::fs::write(dir.join(path), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn diamond_include_is_visible_to_every_includer() {
+        let dir = write_specs(
+            "diamond",
+            &[
+                (
+                    "a.toml",
+                    "[spec]\nname = \"A\"\ninclude = [\"b.toml\", \"c.toml\"]\n[spec.types]\n",
+                ),
+                (
+                    "b.toml",
+                    "[spec]\nname = \"B\"\ninclude = [\"d.toml\"]\n\
+                     [spec.types]\nradio = { key = \"radio\" }\n\
+                     [radio.lora]\ndescription = \"LoRa\"\ndependencies = [\"board:dk\"]\n",
+                ),
+                (
+                    "c.toml",
+                    "[spec]\nname = \"C\"\ninclude = [\"d.toml\"]\n\
+                     [spec.types]\nsensor = { key = \"sensor\" }\n\
+                     [sensor.temp]\ndescription = \"Temperature\"\ndependencies = [\"board:dk\"]\n",
+                ),
+                (
+                    "d.toml",
+                    "[spec]\nname = \"D\"\n\
+                     [spec.types]\nboard = { key = \"board\", single = true }\n\
+                     [board.dk]\ndescription = \"DK\"\n",
+                ),
+            ],
+        );
+
+        let spec = Spec::parse_path(dir.join("a.toml")).unwrap();
+        assert_eq!(spec.name, "A");
+        let types = spec
+            .types
+            .values()
+            .map(|x| x.key.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(types, ["board", "radio", "sensor"]);
+        assert!(spec.field(&TypeKey::new("board".into()), "dk").is_some());
+        assert!(spec.field(&TypeKey::new("sensor".into()), "temp").is_some());
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = write_specs(
+            "cycle",
+            &[
+                (
+                    "a.toml",
+                    "[spec]\nname = \"A\"\ninclude = [\"b.toml\"]\n[spec.types]\n",
+                ),
+                (
+                    "b.toml",
+                    "[spec]\nname = \"B\"\ninclude = [\"a.toml\"]\n[spec.types]\n",
+                ),
+            ],
+        );
+
+        match Spec::parse_path(dir.join("a.toml")) {
+            Err(Error::Include(path, e)) => {
+                assert!(path.ends_with("b.toml"));
+                assert!(matches!(*e, Error::Include(_, ref e) if matches!(**e, Error::Cycle(_))));
+            }
+            other => panic!("expected a cycle error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn u64_values_above_i64_max_are_written_as_strings() {
+        let max = toml::Value::String(u64::MAX.to_string());
+        assert_eq!(Value::new(Type::U64, &max), Some(Value::U64(u64::MAX)));
+        assert_eq!(
+            Value::new(Type::U64, &toml::Value::Integer(5)),
+            Some(Value::U64(5))
+        );
+        assert_eq!(Value::new(Type::U64, &toml::Value::Integer(-1)), None);
+        assert_eq!(
+            Value::new(Type::U64, &toml::Value::String("x".into())),
+            None
+        );
+        assert_eq!(
+            Value::new(Type::U32, &toml::Value::String("5".into())),
+            None
+        );
+    }
+
+    #[test]
+    fn cfg_prefix_must_be_an_identifier() {
+        let err = Spec::parse_str("[spec]\nname = \"P\"\ncfg_prefix = \"pb-\"\n[spec.types]\n")
+            .unwrap_err();
+        assert!(matches!(err, Error::Spec(SpecError::InvalidCfgPrefix(x)) if x == "pb-"));
+    }
+
+    #[test]
+    fn included_types_use_the_root_cfg_settings() {
+        let dir = write_specs(
+            "inherit",
+            &[
+                (
+                    "root.toml",
+                    "[spec]\nname = \"Root\"\ninclude = [\"radio.toml\"]\n\
+                     cfg_prefix = \"pb_\"\ncfg_style = \"key-value\"\n\
+                     [spec.types]\nboard = { key = \"board\" }\n\
+                     [board.dk]\ndescription = \"DK\"\n",
+                ),
+                (
+                    "radio.toml",
+                    "[spec]\nname = \"Radio\"\n\
+                     [spec.types]\nradio = { key = \"radio\" }\n\
+                     [radio.lora]\ndescription = \"LoRa\"\n\
+                     [radio.lora.properties]\npower = { type = \"u8\", default = 10 }\n",
+                ),
+            ],
+        );
+        let spec = Spec::parse_path(dir.join("root.toml")).unwrap();
+
+        assert_eq!(
+            spec.check_cfg_flags()
+                .into_iter()
+                .filter(|x| x != "--check-cfg")
+                .collect::<Vec<_>>(),
+            [
+                "cfg(pb_radio,values(\"lora\"))",
+                "cfg(pb_radio_lora_power,values(any()))",
+                "cfg(pb_board,values(\"dk\"))",
+            ]
+        );
+
+        let profile = crate::profile::Profile::parse_str(
+            &spec,
+            "[profile]\ndescription = \"P\"\nbins = [\"app\"]\n\
+             [config]\n[board]\ndk = true\n[radio]\nlora = {}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            profile.rustc_cfg_flags(),
+            [
+                "--cfg",
+                "pb_board=\"dk\"",
+                "--cfg",
+                "pb_radio=\"lora\"",
+                "--cfg",
+                "pb_radio_lora_power=\"10\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn included_files_must_not_change_cfg_settings() {
+        let dir = write_specs(
+            "inherit-mismatch",
+            &[
+                (
+                    "root.toml",
+                    "[spec]\nname = \"Root\"\ninclude = [\"radio.toml\"]\n\
+                     cfg_prefix = \"pb_\"\n[spec.types]\n",
+                ),
+                (
+                    "radio.toml",
+                    "[spec]\nname = \"Radio\"\ncfg_prefix = \"radio_\"\n[spec.types]\n",
+                ),
+            ],
+        );
+        match Spec::parse_path(dir.join("root.toml")).unwrap_err() {
+            Error::Include(_, e) => assert!(matches!(
+                *e,
+                Error::Spec(SpecError::InheritedMismatch("cfg_prefix", ref x, ref y))
+                    if x == "radio_" && y == "pb_"
+            )),
+            other => panic!("expected an include error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn colliding_cfg_names_are_rejected() {
+        let err = Spec::parse_str(
+            "[spec]\nname = \"P\"\n\
+             [spec.types]\nboard = { key = \"board\", single = true }\n\
+             board_nrf52 = { key = \"board_nrf52\", single = true }\n\
+             [board.nrf52_dk]\ndescription = \"DK\"\n\
+             [board.nrf52_dk.properties]\nx = { type = \"u8\" }\n\
+             [board_nrf52.dk]\ndescription = \"DK\"\n\
+             [board_nrf52.dk.properties]\nx = { type = \"u8\" }\n",
+        )
+        .unwrap_err();
+        match &err {
+            Error::CfgCollision { name, .. } => assert_eq!(name, "board_nrf52_dk_x"),
+            other => panic!("expected a collision, got {:?}", other),
+        }
+        let message = err.to_string();
+        assert!(message.contains("`board.nrf52_dk.x`"), "{}", message);
+        assert!(message.contains("`board_nrf52.dk.x`"), "{}", message);
+    }
+
+    #[test]
+    fn as_is_cfg_names_must_be_identifiers() {
+        let err = Spec::parse_str(
+            "[spec]\nname = \"P\"\ncfg_style = \"as-is\"\n\
+             [spec.types]\nboard = { key = \"board\" }\n\
+             [board.nrf52-dk]\ndescription = \"DK\"\n",
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidCfgName { name, .. } if name == "board_nrf52-dk"));
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use crate::workspace::Workspace;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not access state file `{0}`.")]
+    Io(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not parse state file `{0}`.")]
+    Json(PathBuf, #[source] serde_json::Error),
+}
+
+/// Untracked, per-workspace state kept under the target directory, such as
+/// the currently activated profile.
+#[derive(Debug)]
+pub struct State {
+    path: PathBuf,
+    data: serde_json::Map<String, serde_json::Value>,
+}
+
+impl State {
+    pub fn load(workspace: &Workspace) -> Result<State, Error> {
+        let path = workspace.state_path();
+        let data = match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| Error::Json(path.clone(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(Error::Io(path, e)),
+        };
+        Ok(State { path, data })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.data.get(key)
+    }
+
+    pub fn set(&mut self, key: &str, value: serde_json::Value) {
+        self.data.insert(key.to_string(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<serde_json::Value> {
+        self.data.remove(key)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| Error::Io(self.path.clone(), e))?;
+        }
+        let s = serde_json::to_string_pretty(&self.data).unwrap();
+        std::fs::write(&self.path, s).map_err(|e| Error::Io(self.path.clone(), e))
+    }
+}
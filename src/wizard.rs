@@ -0,0 +1,460 @@
+use std::io::{self, BufRead, Write};
+
+use indexmap::IndexMap;
+
+use crate::{
+    profile::DEFAULT_SPEC,
+    spec::{Dep, FieldKey, PropSpec, Spec, Type, TypeIndex, TypeKey, Value},
+};
+
+/// Asks questions on `output` and reads answers from `input`, one per line.
+struct Prompter<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    fn say(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.output, "{}", line)
+    }
+
+    /// Asks a question, returning the trimmed answer, or `default` if the
+    /// answer is empty.
+    fn ask(&mut self, question: &str, default: Option<&str>) -> io::Result<String> {
+        match default {
+            Some(v) if !v.is_empty() => write!(self.output, "{} [{}]: ", question, v)?,
+            _ => write!(self.output, "{}: ", question)?,
+        }
+        self.output.flush()?;
+
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "input ended before the profile was complete",
+            ));
+        }
+        let line = line.trim();
+        Ok(match default {
+            Some(v) if line.is_empty() => v.to_string(),
+            _ => line.to_string(),
+        })
+    }
+
+    fn ask_list(&mut self, question: &str) -> io::Result<Vec<String>> {
+        Ok(split_list(&self.ask(question, None)?))
+    }
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Fields chosen so far, by type index.
+type Selection = IndexMap<TypeIndex, Vec<FieldKey>>;
+
+/// Walks through a spec on the terminal, asking for the profile's details,
+/// a field for each single type, any fields of each other type and values for
+/// the chosen fields' properties, and returns the profile as TOML.
+///
+/// Fields whose dependencies are already known to be unmet cannot be chosen.
+/// Dependencies on types asked about later are checked at the end, and the
+/// types are asked about again, with the previous answers as defaults, until
+/// every dependency is met.
+pub fn new_profile<R: BufRead, W: Write>(
+    spec_name: &str,
+    spec: &Spec,
+    input: R,
+    output: W,
+) -> io::Result<String> {
+    let mut p = Prompter { input, output };
+
+    let description = loop {
+        let v = p.ask("Description", None)?;
+        if !v.is_empty() {
+            break v;
+        }
+        p.say("A description is required.")?;
+    };
+    let (bins, libs) = loop {
+        let bins = p.ask_list("Binaries to build (comma separated)")?;
+        if !bins.is_empty() {
+            break (bins, vec![]);
+        }
+        let libs = p.ask_list("Libraries to build (comma separated)")?;
+        if !libs.is_empty() {
+            break (vec![], libs);
+        }
+        p.say("At least one binary or library is required.")?;
+    };
+    let features = p.ask_list("Cargo features (comma separated)")?;
+    let target = p.ask("Target triple (empty for the host)", None)?;
+
+    let mut selection = Selection::new();
+    loop {
+        let mut visited = vec![];
+        for (index, ty) in spec.types.iter() {
+            let has_fields = spec.fields.get(index).is_some_and(|x| !x.is_empty());
+            if !has_fields {
+                continue;
+            }
+            visited.push(ty.key.clone());
+            let chosen = choose_fields(&mut p, spec, index, &selection, &visited)?;
+            selection.insert(index.clone(), chosen);
+        }
+
+        let unmet = unmet_dependencies(spec, &selection);
+        if unmet.is_empty() {
+            break;
+        }
+        p.say("")?;
+        for line in unmet.iter() {
+            p.say(line)?;
+        }
+        p.say("Choose again; your previous answers are the defaults.")?;
+    }
+
+    let mut properties = IndexMap::new();
+    for (index, fields) in selection.iter() {
+        let ty = &spec.types[index];
+        for field in fields.iter() {
+            let field_spec = &spec.fields[index][field];
+            if field_spec.properties.is_empty() {
+                continue;
+            }
+            p.say(&format!("\nProperties of `{}.{}`:", ty.key, field))?;
+            let mut values = toml::map::Map::new();
+            for (name, prop) in field_spec.properties.iter() {
+                if let Some(v) = ask_property(&mut p, name, prop)? {
+                    values.insert(name.to_string(), v);
+                }
+            }
+            properties.insert((index.clone(), field.clone()), values);
+        }
+    }
+
+    let mut profile = toml::map::Map::new();
+    profile.insert("description".into(), description.into());
+    if spec_name != DEFAULT_SPEC {
+        profile.insert("spec".into(), spec_name.into());
+    }
+    for (key, values) in [("bins", bins), ("libs", libs), ("features", features)] {
+        if !values.is_empty() {
+            profile.insert(key.into(), values.into());
+        }
+    }
+    if !target.is_empty() {
+        profile.insert("target".into(), target.into());
+    }
+
+    let mut config = toml::map::Map::new();
+    let mut sections = toml::map::Map::new();
+    for (index, fields) in selection.iter() {
+        let ty = &spec.types[index];
+        let mut section = toml::map::Map::new();
+        for field in fields.iter() {
+            let props = properties.remove(&(index.clone(), field.clone()));
+            if ty.is_single {
+                config.insert(ty.key.to_string(), field.as_str().into());
+                if let Some(props) = props.filter(|x| !x.is_empty()) {
+                    section.insert(field.to_string(), props.into());
+                }
+            } else {
+                match props.filter(|x| !x.is_empty()) {
+                    Some(props) => section.insert(field.to_string(), props.into()),
+                    None => section.insert(field.to_string(), true.into()),
+                };
+            }
+        }
+        if !section.is_empty() {
+            sections.insert(index.to_string(), section.into());
+        }
+    }
+
+    let mut out = toml::map::Map::new();
+    out.insert("profile".into(), profile.into());
+    out.insert("config".into(), config.into());
+    out.extend(sections);
+    Ok(toml::to_string(&toml::Value::Table(out)).unwrap())
+}
+
+fn is_selected(spec: &Spec, selection: &Selection, dep: &Dep) -> bool {
+    spec.find_type(&dep.ty)
+        .and_then(|(index, _)| selection.get(index))
+        .is_some_and(|x| x.iter().any(|f| f.as_str() == dep.name))
+}
+
+/// Describes each chosen field whose dependencies are not met.
+fn unmet_dependencies(spec: &Spec, selection: &Selection) -> Vec<String> {
+    let mut out = vec![];
+    for (index, fields) in selection.iter() {
+        let ty = &spec.types[index];
+        for field in fields.iter() {
+            let deps = &*spec.fields[index][field].dependencies;
+            if !deps.is_satisfied(|x| is_selected(spec, selection, x)) {
+                out.push(format!("`{}.{}` requires {}.", ty.key, field, deps));
+            }
+        }
+    }
+    out
+}
+
+fn choose_fields<R: BufRead, W: Write>(
+    p: &mut Prompter<R, W>,
+    spec: &Spec,
+    index: &TypeIndex,
+    selection: &Selection,
+    visited: &[TypeKey],
+) -> io::Result<Vec<FieldKey>> {
+    let ty = &spec.types[index];
+    let fields = &spec.fields[index];
+    let names = fields.keys().collect::<Vec<_>>();
+
+    // Previous answers, plus anything already chosen fields require.
+    let mut defaults = selection.get(index).cloned().unwrap_or_default();
+    for (other, chosen) in selection.iter() {
+        for field in chosen.iter() {
+            for dep in spec.fields[other][field].dependencies.required_deps() {
+                let is_known = names.iter().any(|x| x.as_str() == dep.name);
+                if dep.ty == ty.key && is_known && !defaults.iter().any(|x| x.as_str() == dep.name)
+                {
+                    defaults.push(FieldKey::new(dep.name.clone()));
+                }
+            }
+        }
+    }
+    if ty.is_single {
+        defaults.truncate(1);
+    }
+
+    p.say("")?;
+    if ty.is_single {
+        p.say(&format!("`{}`: choose one (`none` for none)", ty.key))?;
+    } else {
+        p.say(&format!(
+            "`{}`: choose any, separated by commas (`none` for none)",
+            ty.key
+        ))?;
+    }
+    for (i, (name, field)) in fields.iter().enumerate() {
+        let mut line = format!("  {}) {}", i + 1, name);
+        if !field.description.is_empty() {
+            line.push_str(&format!(" - {}", field.description));
+        }
+        if !field.dependencies.deps().is_empty() {
+            line.push_str(&format!(" (requires {})", *field.dependencies));
+        }
+        p.say(&line)?;
+    }
+
+    loop {
+        let defaults = defaults.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        let answer = p.ask("Choice", Some(&defaults.join(",")))?;
+        let mut chosen = vec![];
+        let mut invalid = None;
+        if answer != "none" {
+            for item in split_list(&answer) {
+                let name = match item.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= names.len() => names[n - 1].clone(),
+                    _ => match names.iter().find(|x| x.as_str() == item) {
+                        Some(v) => (*v).clone(),
+                        None => {
+                            invalid = Some(format!("`{}` is not one of the choices.", item));
+                            break;
+                        }
+                    },
+                };
+                if !chosen.contains(&name) {
+                    chosen.push(name);
+                }
+            }
+        }
+        if invalid.is_none() && ty.is_single && chosen.len() > 1 {
+            invalid = Some(format!("`{}` is a single type; choose one field.", ty.key));
+        }
+
+        // Check what can be checked now: fields of types not yet asked about
+        // may still be chosen later.
+        if invalid.is_none() {
+            let mut candidate = selection.clone();
+            candidate.insert(index.clone(), chosen.clone());
+            let possible =
+                |dep: &Dep| !visited.contains(&dep.ty) || is_selected(spec, &candidate, dep);
+            for name in chosen.iter() {
+                let deps = &*fields[name].dependencies;
+                if !deps.is_satisfied(possible) {
+                    invalid = Some(format!("`{}.{}` requires {}.", ty.key, name, deps));
+                    break;
+                }
+            }
+        }
+
+        match invalid {
+            Some(message) => p.say(&message)?,
+            None => return Ok(chosen),
+        }
+    }
+}
+
+/// Asks for a property's value, returning `None` to leave it unset or at
+/// its default.
+fn ask_property<R: BufRead, W: Write>(
+    p: &mut Prompter<R, W>,
+    name: &str,
+    prop: &PropSpec,
+) -> io::Result<Option<toml::Value>> {
+    let mut hints = vec![prop.ty.as_str().to_string()];
+    match (prop.min.as_ref(), prop.max.as_ref()) {
+        (Some(min), Some(max)) => hints.push(format!("{} to {}", min, max)),
+        (Some(min), None) => hints.push(format!("at least {}", min)),
+        (None, Some(max)) => hints.push(format!("at most {}", max)),
+        (None, None) => {}
+    }
+    if !prop.values.is_empty() {
+        let values = prop
+            .values
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        hints.push(format!("one of {}", values.join(", ")));
+    }
+
+    let default = prop.default.as_ref().map(|x| x.to_string());
+    loop {
+        let answer = p.ask(
+            &format!("  {} ({})", name, hints.join("; ")),
+            default.as_deref(),
+        )?;
+        if answer.is_empty() || Some(&answer) == default.as_ref() {
+            return Ok(None);
+        }
+
+        let raw = if prop.ty == Type::Bool {
+            answer.parse::<bool>().ok().map(toml::Value::Boolean)
+        } else if prop.ty.is_integer() {
+            match answer.parse::<i64>() {
+                Ok(v) => Some(toml::Value::Integer(v)),
+                // Only a string can hold a `u64` above `i64::MAX` in TOML.
+                Err(_) if prop.ty == Type::U64 && answer.parse::<u64>().is_ok() => {
+                    Some(toml::Value::String(answer.clone()))
+                }
+                Err(_) => None,
+            }
+        } else {
+            Some(toml::Value::String(answer.clone()))
+        };
+        let value = match raw.as_ref().and_then(|x| Value::new(prop.ty, x)) {
+            Some(v) => v,
+            None => {
+                p.say(&format!(
+                    "`{}` is not a valid {}.",
+                    answer,
+                    prop.ty.as_str()
+                ))?;
+                continue;
+            }
+        };
+        match prop.check(&value) {
+            Ok(()) => return Ok(raw),
+            Err(e) => p.say(&format!("{}.", e))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profile;
+
+    const SPEC: &str = r#"
+[spec]
+name = "Test"
+
+[spec.types]
+radio = { key = "radio" }
+board = { key = "board", single = true }
+
+[radio.lora]
+description = "LoRa"
+dependencies = ["board:dk"]
+
+[radio.lora.properties]
+id = { type = "u64" }
+
+[radio.ble]
+description = "BLE"
+
+[board.dk]
+description = "DK"
+
+[board.custom]
+description = "Custom"
+"#;
+
+    fn run(spec: &Spec, answers: &[&str]) -> (String, String) {
+        let input = answers
+            .iter()
+            .map(|x| format!("{}\n", x))
+            .collect::<String>();
+        let mut output = vec![];
+        let profile = new_profile("main", spec, input.as_bytes(), &mut output).unwrap();
+        (profile, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn creates_a_loadable_profile() {
+        let spec = Spec::parse_str(SPEC).unwrap();
+        let (raw, output) = run(
+            &spec,
+            &[
+                "",
+                "Test",
+                "app",
+                "extra",
+                "",
+                "1,ble,nope",
+                "lora,2",
+                "3",
+                "dk",
+                "x",
+                "18446744073709551615",
+            ],
+        );
+
+        assert!(output.contains("A description is required."));
+        assert!(output.contains("`nope` is not one of the choices."));
+        assert!(output.contains("`3` is not one of the choices."));
+        assert!(output.contains("`x` is not a valid u64."));
+
+        let profile = Profile::parse_str(&spec, &raw).unwrap();
+        assert_eq!(profile.description, "Test");
+        assert_eq!(profile.bins, ["app"]);
+        assert_eq!(profile.features, ["extra"]);
+        assert!(profile.is_enabled(&TypeKey::new("radio".into()), "lora"));
+        assert!(profile.is_enabled(&TypeKey::new("radio".into()), "ble"));
+        assert!(profile.is_enabled(&TypeKey::new("board".into()), "dk"));
+        let props = &profile.config[&TypeKey::new("radio".into())][&FieldKey::new("lora".into())];
+        assert!(matches!(props["id"], Value::U64(u64::MAX)));
+    }
+
+    #[test]
+    fn asks_again_until_dependencies_are_met() {
+        let spec = Spec::parse_str(SPEC).unwrap();
+        let (raw, output) = run(
+            &spec,
+            &["Test", "app", "", "", "lora", "custom", "", "dk", ""],
+        );
+
+        assert!(output.contains("`radio.lora` requires board:dk."));
+        assert!(output.contains("Choice [lora]: "));
+        assert!(output.contains("Choice [custom]: "));
+
+        let profile = Profile::parse_str(&spec, &raw).unwrap();
+        assert!(profile.unsatisfied_dependencies().is_empty());
+        assert!(profile.is_enabled(&TypeKey::new("board".into()), "dk"));
+    }
+}
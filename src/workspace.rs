@@ -0,0 +1,465 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    build::ArtifactName,
+    lint::{Level, Levels, Lint},
+    profile::Profile,
+    spec::Spec,
+    state::{self, State},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not read manifest.")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not parse manifest TOML.")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Could not find a Cargo.toml in the current directory or any parent directory.")]
+    ManifestNotFound,
+
+    #[error("`{0}` in [{1}] is not of type `{2}`.")]
+    InvalidMetadataType(&'static str, String, &'static str),
+
+    #[error("Invalid entry in [{0}]: {1}.")]
+    InvalidLint(String, String),
+
+    #[error("Invalid `{0}` in [{1}]: {2}.")]
+    InvalidMetadataValue(&'static str, String, String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+
+    #[error("No spec found with the name `{0}`.")]
+    SpecNotFound(String),
+
+    #[error("No profile found with the name `{0}`.")]
+    ProfileNotFound(String),
+
+    #[error("Profile `{profile}` uses spec `{spec}`, but no spec with that name was found.")]
+    MissingSpec { profile: String, spec: String },
+
+    #[error("Error in spec `{0}`.")]
+    Spec(String, #[source] crate::spec::Error),
+
+    #[error("Error in profile `{0}`.")]
+    Profile(String, #[source] crate::profile::Error),
+}
+
+/// The Cargo workspace the tool is operating on, with the directory layout
+/// resolved from `[workspace.metadata.pbuild]` and `[package.metadata.pbuild]`.
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub root: PathBuf,
+    pub profiles_dir: PathBuf,
+    pub specs_dir: PathBuf,
+    pub dist_dir: PathBuf,
+    /// Cargo's target directory.
+    pub target_dir: PathBuf,
+    pub artifact_name: ArtifactName,
+    pub default_profile: Option<String>,
+    pub lints: Levels,
+}
+
+/// Where the current profile was selected from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileSource {
+    /// The `PBUILD_PROFILE` environment variable.
+    Env,
+    /// `cargo pbuild use`.
+    Selected,
+    /// `default-profile` in the manifest metadata.
+    Default,
+}
+
+impl Display for ProfileSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ProfileSource::Env => "set by PBUILD_PROFILE",
+            ProfileSource::Selected => "selected with `cargo pbuild use`",
+            ProfileSource::Default => "default from Cargo.toml metadata",
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Metadata {
+    profiles_dir: Option<PathBuf>,
+    specs_dir: Option<PathBuf>,
+    dist_dir: Option<PathBuf>,
+    artifact_name: Option<ArtifactName>,
+    default_profile: Option<String>,
+    lints: Levels,
+}
+
+impl Metadata {
+    fn parse(
+        manifest: &Path,
+        raw: &toml::Value,
+        table: &'static str,
+    ) -> Result<Option<Metadata>, Error> {
+        let section = format!("{}.metadata.pbuild", table);
+        let pbuild = match raw
+            .get(table)
+            .and_then(|x| x.get("metadata"))
+            .and_then(|x| x.get("pbuild"))
+        {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        let base = manifest.parent().unwrap_or_else(|| Path::new("."));
+        let get_path = |key: &'static str| -> Result<Option<PathBuf>, Error> {
+            match pbuild.get(key) {
+                Some(v) => v
+                    .as_str()
+                    .map(|x| Some(base.join(x)))
+                    .ok_or_else(|| Error::InvalidMetadataType(key, section.clone(), "string")),
+                None => Ok(None),
+            }
+        };
+
+        let mut lints = Levels::default();
+        if let Some(raw_lints) = pbuild.get("lints") {
+            let lints_section = format!("{}.lints", section);
+            let raw_lints = raw_lints.as_table().ok_or_else(|| {
+                Error::InvalidMetadataType("lints", section.clone(), "map<string, string>")
+            })?;
+            for (k, v) in raw_lints.iter() {
+                let lint = k
+                    .parse::<Lint>()
+                    .map_err(|e| Error::InvalidLint(lints_section.clone(), e))?;
+                let level = v
+                    .as_str()
+                    .ok_or_else(|| {
+                        Error::InvalidLint(
+                            lints_section.clone(),
+                            format!("`{}` is not a string", k),
+                        )
+                    })?
+                    .parse::<Level>()
+                    .map_err(|e| Error::InvalidLint(lints_section.clone(), e))?;
+                lints.set(lint, level);
+            }
+        }
+
+        let default_profile = match pbuild.get("default-profile") {
+            Some(v) => Some(v.as_str().map(str::to_string).ok_or_else(|| {
+                Error::InvalidMetadataType("default-profile", section.clone(), "string")
+            })?),
+            None => None,
+        };
+
+        let artifact_name = match pbuild.get("artifact-name") {
+            Some(v) => Some(
+                v.as_str()
+                    .ok_or_else(|| {
+                        Error::InvalidMetadataType("artifact-name", section.clone(), "string")
+                    })?
+                    .parse::<ArtifactName>()
+                    .map_err(|e| {
+                        Error::InvalidMetadataValue("artifact-name", section.clone(), e)
+                    })?,
+            ),
+            None => None,
+        };
+
+        Ok(Some(Metadata {
+            profiles_dir: get_path("profiles-dir")?,
+            specs_dir: get_path("specs-dir")?,
+            dist_dir: get_path("dist-dir")?,
+            artifact_name,
+            default_profile,
+            lints,
+        }))
+    }
+
+    fn merge(self, other: Metadata) -> Metadata {
+        let mut lints = self.lints;
+        lints.merge(&other.lints);
+        Metadata {
+            profiles_dir: other.profiles_dir.or(self.profiles_dir),
+            specs_dir: other.specs_dir.or(self.specs_dir),
+            dist_dir: other.dist_dir.or(self.dist_dir),
+            artifact_name: other.artifact_name.or(self.artifact_name),
+            default_profile: other.default_profile.or(self.default_profile),
+            lints,
+        }
+    }
+}
+
+impl Workspace {
+    /// Finds the workspace containing the current directory.
+    ///
+    /// Uses `cargo locate-project` where possible, and otherwise walks up from
+    /// `CARGO_MANIFEST_DIR` (or the current directory) looking for manifests.
+    pub fn locate() -> Result<Workspace, Error> {
+        let (workspace_manifest, package_manifest) =
+            match (locate_project(true), locate_project(false)) {
+                (Some(ws), pkg) => (ws, pkg),
+                _ => walk_manifests()?,
+            };
+
+        Self::from_manifests(&workspace_manifest, package_manifest.as_deref())
+    }
+
+    fn from_manifests(
+        workspace_manifest: &Path,
+        package_manifest: Option<&Path>,
+    ) -> Result<Workspace, Error> {
+        let root = workspace_manifest
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf();
+
+        let raw: toml::Value = toml::from_str(&std::fs::read_to_string(workspace_manifest)?)?;
+        let mut metadata =
+            Metadata::parse(workspace_manifest, &raw, "workspace")?.unwrap_or_default();
+
+        if let Some(path) = package_manifest {
+            let raw = if path == workspace_manifest {
+                raw
+            } else {
+                toml::from_str(&std::fs::read_to_string(path)?)?
+            };
+            if let Some(package) = Metadata::parse(path, &raw, "package")? {
+                metadata = metadata.merge(package);
+            }
+        }
+
+        let profiles_dir = metadata
+            .profiles_dir
+            .unwrap_or_else(|| root.join("profiles"));
+        let specs_dir = metadata
+            .specs_dir
+            .unwrap_or_else(|| profiles_dir.join("specs"));
+        let dist_dir = metadata.dist_dir.unwrap_or_else(|| root.join("dist"));
+        let target_dir = match target_directory(workspace_manifest) {
+            Some(v) => v,
+            None => match std::env::var_os("CARGO_TARGET_DIR") {
+                Some(v) => std::env::current_dir()?.join(v),
+                None => root.join("target"),
+            },
+        };
+
+        Ok(Workspace {
+            root,
+            profiles_dir,
+            specs_dir,
+            dist_dir,
+            target_dir,
+            artifact_name: metadata.artifact_name.unwrap_or_default(),
+            default_profile: metadata.default_profile,
+            lints: metadata.lints,
+        })
+    }
+}
+
+impl Workspace {
+    /// The profile to use when none is given on the command line: the
+    /// `PBUILD_PROFILE` environment variable, then the profile chosen with
+    /// `cargo pbuild use`, then `default-profile` from the metadata.
+    pub fn current_profile(&self) -> Result<Option<(String, ProfileSource)>, state::Error> {
+        if let Ok(v) = std::env::var("PBUILD_PROFILE") {
+            if !v.is_empty() {
+                return Ok(Some((v, ProfileSource::Env)));
+            }
+        }
+
+        if let Some(v) = self.selected_profile()? {
+            return Ok(Some((v, ProfileSource::Selected)));
+        }
+
+        Ok(self
+            .default_profile
+            .clone()
+            .map(|x| (x, ProfileSource::Default)))
+    }
+
+    /// The profile persisted by `cargo pbuild use`, if any.
+    pub fn selected_profile(&self) -> Result<Option<String>, state::Error> {
+        Ok(State::load(self)?
+            .get("selected")
+            .and_then(|x| x.as_str())
+            .map(str::to_string))
+    }
+
+    /// Persists the profile to use when none is given, or clears it.
+    pub fn select_profile(&self, name: Option<&str>) -> Result<(), state::Error> {
+        let mut state = State::load(self)?;
+        match name {
+            Some(v) => state.set("selected", v.into()),
+            None => {
+                state.remove("selected");
+            }
+        }
+        state.save()
+    }
+
+    pub fn spec_path(&self, name: &str) -> PathBuf {
+        self.specs_dir.join(format!("{}.toml", name))
+    }
+
+    pub fn profile_path(&self, name: &str) -> PathBuf {
+        self.profiles_dir.join(format!("{}.toml", name))
+    }
+
+    /// The target directory a profile builds into. Each profile has its own,
+    /// so that building several profiles does not invalidate each other's
+    /// caches.
+    pub fn profile_target_dir(&self, name: &str) -> PathBuf {
+        self.target_dir.join("pbuild").join(name)
+    }
+
+    /// Where a profile's binaries are collected after a build.
+    pub fn profile_dist_dir(&self, name: &str) -> PathBuf {
+        self.dist_dir.join(name)
+    }
+
+    /// The untracked file local state such as the active profile is kept in.
+    pub fn state_path(&self) -> PathBuf {
+        self.target_dir.join("pbuild").join("state.json")
+    }
+
+    /// Names of all specs in the specs directory, sorted.
+    pub fn spec_names(&self) -> Result<Vec<String>, LoadError> {
+        Ok(toml_stems(&self.specs_dir)?)
+    }
+
+    /// Names of all profiles in the profiles directory, sorted.
+    pub fn profile_names(&self) -> Result<Vec<String>, LoadError> {
+        Ok(toml_stems(&self.profiles_dir)?)
+    }
+
+    pub fn load_spec(&self, name: &str) -> Result<Spec, LoadError> {
+        let path = self.spec_path(name);
+        if !path.is_file() {
+            return Err(LoadError::SpecNotFound(name.to_string()));
+        }
+        Spec::parse_path(&path).map_err(|e| LoadError::Spec(name.to_string(), e))
+    }
+
+    /// Loads a single profile along with the spec it uses, without touching
+    /// any other profile.
+    pub fn load_profile(&self, name: &str) -> Result<Profile, LoadError> {
+        let path = self.profile_path(name);
+        if !path.is_file() {
+            return Err(LoadError::ProfileNotFound(name.to_string()));
+        }
+
+        let raw = std::fs::read_to_string(&path)?;
+        let spec_name =
+            Profile::spec_name(&raw).map_err(|e| LoadError::Profile(name.to_string(), e))?;
+        let spec = match self.load_spec(&spec_name) {
+            Ok(v) => v,
+            Err(LoadError::SpecNotFound(spec)) => {
+                return Err(LoadError::MissingSpec {
+                    profile: name.to_string(),
+                    spec,
+                })
+            }
+            Err(e) => return Err(e),
+        };
+
+        Profile::parse_str(&spec, &raw).map_err(|e| LoadError::Profile(name.to_string(), e))
+    }
+}
+
+fn toml_stems(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut out = vec![];
+    for item in std::fs::read_dir(dir)?.filter_map(Result::ok) {
+        let p = item.path();
+        if p.is_file() && p.extension().and_then(|x| x.to_str()) == Some("toml") {
+            if let Some(stem) = p.file_stem().and_then(|x| x.to_str()) {
+                out.push(stem.to_string());
+            }
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+fn locate_project(workspace: bool) -> Option<PathBuf> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let mut cmd = Command::new(cargo);
+    cmd.args(["locate-project", "--message-format", "plain"]);
+    if workspace {
+        cmd.arg("--workspace");
+    }
+
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let path = String::from_utf8(output.stdout).ok()?;
+    Some(PathBuf::from(path.trim()))
+}
+
+/// The workspace's target directory as reported by `cargo metadata`, which
+/// accounts for `CARGO_TARGET_DIR` and `build.target-dir` the way cargo
+/// itself does.
+fn target_directory(manifest: &Path) -> Option<PathBuf> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let output = Command::new(cargo)
+        .args([
+            "metadata",
+            "--format-version",
+            "1",
+            "--no-deps",
+            "--manifest-path",
+        ])
+        .arg(manifest)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    metadata
+        .get("target_directory")?
+        .as_str()
+        .map(PathBuf::from)
+}
+
+/// Returns the nearest manifest declaring a `[workspace]` (or the nearest
+/// manifest if none do) and the nearest manifest.
+fn walk_manifests() -> Result<(PathBuf, Option<PathBuf>), Error> {
+    let start = match std::env::var_os("CARGO_MANIFEST_DIR") {
+        Some(v) => PathBuf::from(v),
+        None => std::env::current_dir()?,
+    };
+
+    let mut nearest = None;
+    let mut workspace = None;
+
+    for dir in start.ancestors() {
+        let manifest = dir.join("Cargo.toml");
+        if !manifest.is_file() {
+            continue;
+        }
+
+        let raw: toml::Value = toml::from_str(&std::fs::read_to_string(&manifest)?)?;
+        if workspace.is_none() && raw.get("workspace").is_some() {
+            workspace = Some(manifest.clone());
+        }
+        if nearest.is_none() {
+            nearest = Some(manifest);
+        }
+    }
+
+    match (workspace, nearest) {
+        (Some(ws), nearest) => Ok((ws, nearest)),
+        (None, Some(nearest)) => Ok((nearest.clone(), Some(nearest))),
+        (None, None) => Err(Error::ManifestNotFound),
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// A one-binary package with a single profile, committed to a fresh git
+/// repository under the system temp dir.
+fn workspace(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pbuild-build-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let files = [
+        (
+            "Cargo.toml",
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        ),
+        ("src/main.rs", "fn main() {}\n"),
+        (
+            "profiles/specs/main.toml",
+            "[spec]\nname = \"Main\"\n\n[spec.types]\nboard = { key = \"board\", single = true }\n\n\
+             [board.dk]\ndescription = \"DK\"\n",
+        ),
+        (
+            "profiles/dev.toml",
+            "[profile]\ndescription = \"Dev\"\nbins = [\"demo\"]\n\n[config]\nboard = \"dk\"\n",
+        ),
+    ];
+    for (path, contents) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    for args in [
+        &["init", "-q"][..],
+        &["add", "."],
+        &[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "init",
+        ],
+    ] {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    dir
+}
+
+fn pbuild(dir: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-pbuild"))
+        .arg("pbuild")
+        .args(args)
+        .current_dir(dir)
+        .env_remove("PBUILD_PROFILE")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn unchanged_profile_is_skipped() {
+    let dir = workspace("skip");
+
+    let first = pbuild(&dir, &["build", "dev", "--skip-unchanged"]);
+    let stdout = String::from_utf8_lossy(&first.stdout);
+    assert!(stdout.contains("Copied"), "{}", stdout);
+
+    let manifest = std::fs::read_to_string(dir.join("dist/dev/manifest.json")).unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    assert_eq!(manifest["git"]["dirty"], serde_json::json!(false));
+
+    let second = pbuild(&dir, &["build", "dev", "--skip-unchanged"]);
+    let stdout = String::from_utf8_lossy(&second.stdout);
+    assert!(
+        stdout.contains("Profile `dev` is unchanged; skipping build."),
+        "{}",
+        stdout
+    );
+
+    // A change to a tracked file means building again.
+    std::fs::write(dir.join("src/main.rs"), "fn main() { println!(); }\n").unwrap();
+    let third = pbuild(&dir, &["build", "dev", "--skip-unchanged"]);
+    assert!(String::from_utf8_lossy(&third.stdout).contains("Copied"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn state_follows_cargo_target_dir() {
+    let dir = workspace("target-dir");
+    std::fs::create_dir_all(dir.join(".cargo")).unwrap();
+    std::fs::write(
+        dir.join(".cargo/config.toml"),
+        "[build]\ntarget-dir = \"out\"\n",
+    )
+    .unwrap();
+
+    pbuild(&dir, &["use", "dev"]);
+    assert!(dir.join("out/pbuild/state.json").is_file());
+
+    // A relative CARGO_TARGET_DIR is resolved from the current directory.
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-pbuild"))
+        .args(["pbuild", "use", "dev"])
+        .current_dir(dir.join("src"))
+        .env_remove("PBUILD_PROFILE")
+        .env("CARGO_TARGET_DIR", "rel")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(dir.join("src/rel/pbuild/state.json").is_file());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}